@@ -0,0 +1,175 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    rc::Rc,
+};
+
+use crate::lexer::{RedirOp, Word};
+
+/// Commands separated by `;` or newlines, run one after another.
+#[derive(Debug, Default)]
+pub struct List {
+    pub items: Vec<AndOr>,
+}
+
+/// Pipelines joined by `&&` and `||`, evaluated from the left.
+#[derive(Debug)]
+pub struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(Connector, Pipeline)>,
+    /// Ended by `&`, so that it runs as a job the shell does not wait for.
+    pub background: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Connector {
+    And,
+    Or,
+}
+
+/// Commands whose output feeds the input of the next one.
+#[derive(Debug)]
+pub struct Pipeline {
+    pub commands: Vec<Command>,
+}
+
+#[derive(Debug)]
+pub enum Command {
+    Simple(SimpleCommand),
+    /// `{ list; }`
+    Group(List),
+    If(If),
+    Function(Function),
+}
+
+/// `if list; then list; [elif list; then list;]... [else list;] fi`
+#[derive(Debug)]
+pub struct If {
+    /// The conditions with the lists that run when they succeed, tried in order.
+    pub branches: Vec<(List, List)>,
+    pub otherwise: Option<List>,
+}
+
+/// `name() compound-command`, which defines a function that runs the command when called.
+#[derive(Debug)]
+pub struct Function {
+    pub name: String,
+    /// Shared with the shell's table of functions, which keeps it after the definition.
+    pub body: Rc<Command>,
+}
+
+#[derive(Debug, Default)]
+pub struct SimpleCommand {
+    /// `NAME=value` words in front of the command.
+    pub assignments: Vec<Assignment>,
+    pub words: Vec<Word>,
+    pub redirects: Vec<Redirect>,
+}
+
+#[derive(Debug)]
+pub struct Assignment {
+    pub name: String,
+    pub value: Word,
+}
+
+#[derive(Debug)]
+pub struct Redirect {
+    pub fd: Option<u32>,
+    pub op: RedirOp,
+    pub target: Word,
+}
+
+// The commands print as the shell source they were parsed from, which is how jobs are
+// listed. Here-documents show their operator only.
+
+impl Display for List {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, and_or) in self.items.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{and_or}")?;
+            if and_or.background {
+                write!(f, " &")?;
+            } else if i + 1 < self.items.len() {
+                write!(f, ";")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for AndOr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.first)?;
+        for (connector, pipeline) in &self.rest {
+            match connector {
+                Connector::And => write!(f, " && {pipeline}")?,
+                Connector::Or => write!(f, " || {pipeline}")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Pipeline {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for (i, command) in self.commands.iter().enumerate() {
+            if i > 0 {
+                write!(f, " | ")?;
+            }
+            write!(f, "{command}")?;
+        }
+        Ok(())
+    }
+}
+
+/// A list inside a compound command, which always ends with a `;` or `&`.
+struct Body<'a>(&'a List);
+
+impl Display for Body<'_> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.0.items.last() {
+            Some(and_or) if and_or.background => write!(f, "{}", self.0),
+            _ => write!(f, "{};", self.0),
+        }
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Simple(cmd) => write!(f, "{cmd}"),
+            Self::Group(list) => write!(f, "{{ {} }}", Body(list)),
+            Self::If(cmd) => {
+                for (i, (condition, body)) in cmd.branches.iter().enumerate() {
+                    let keyword = if i == 0 { "if" } else { " elif" };
+                    write!(f, "{keyword} {} then {}", Body(condition), Body(body))?;
+                }
+                if let Some(otherwise) = &cmd.otherwise {
+                    write!(f, " else {}", Body(otherwise))?;
+                }
+                write!(f, " fi")
+            }
+            Self::Function(function) => write!(f, "{}() {}", function.name, function.body),
+        }
+    }
+}
+
+impl Display for SimpleCommand {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let assignments = self
+            .assignments
+            .iter()
+            .map(|a| format!("{}={}", a.name, a.value));
+        let words = self.words.iter().map(Word::to_string);
+        let redirects = self.redirects.iter().map(|r| {
+            let fd = r.fd.map(|fd| fd.to_string()).unwrap_or_default();
+            match r.op {
+                RedirOp::HereDoc { .. } => format!("{fd}{}", r.op),
+                _ => format!("{fd}{}{}", r.op, r.target),
+            }
+        });
+        let words: Vec<String> = assignments.chain(words).chain(redirects).collect();
+        write!(f, "{}", words.join(" "))
+    }
+}
@@ -0,0 +1,512 @@
+//! Tab completion of the word before the cursor. The command word completes to builtins and
+//! to commands on `PATH`, and a command word with a `/` or any other word to the paths of
+//! files and directories, relative to the working directory, absolute or starting with `~`.
+//! Directories get a trailing `/` and the other names a space, and names are escaped as the
+//! word is quoted. Hidden files only come up for a name that starts with `.`.
+//!
+//! The `complete` builtin sets where the arguments of a command complete from instead: a
+//! list of words, a function or a command that prints the completions one per line, or a
+//! class like files or variables. The spec of a command that has none is read from its file
+//! in `$XDG_CONFIG_HOME/shell/completions`, `~/.config/shell/completions` by default, when
+//! the command is first completed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    fs,
+    ops::Range,
+    path::{Path, PathBuf},
+    slice,
+};
+
+use crate::{expand_tilde, parser, vars::quote_word, Result, Shell, ShellError, BUILTINS};
+
+pub struct Completion {
+    /// What replaces the word.
+    pub text: String,
+    /// What a list of completions shows.
+    pub display: String,
+    /// What kind of completion it is, which a list shows next to it.
+    pub description: Option<&'static str>,
+}
+
+/// The completion specs of commands.
+#[derive(Debug, Default)]
+pub struct Specs {
+    specs: HashMap<String, Vec<Source>>,
+    /// The commands whose spec file was looked for.
+    loaded: HashSet<String>,
+}
+
+/// Where completions come from.
+#[derive(Debug, Clone, PartialEq)]
+enum Source {
+    /// `-W`: the words of a list.
+    Words(String),
+    /// `-F`: the lines a function prints. It gets the command, the word and the word before
+    /// it as arguments, and `COMP_LINE`, `COMP_POINT` and `COMP_CWORD` as variables.
+    Function(String),
+    /// `-C`: the lines a command prints, which it gets the same way as a function.
+    Command(String),
+    Class(Class),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Class {
+    Builtin,
+    Command,
+    Directory,
+    File,
+    Variable,
+}
+
+impl Class {
+    /// The names `-A` takes with the flags that are short for them.
+    const NAMES: [(&'static str, char, Class); 5] = [
+        ("builtin", 'b', Class::Builtin),
+        ("command", 'c', Class::Command),
+        ("directory", 'd', Class::Directory),
+        ("file", 'f', Class::File),
+        ("variable", 'v', Class::Variable),
+    ];
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::NAMES
+            .iter()
+            .find(|(n, ..)| *n == name)
+            .map(|(.., c)| *c)
+    }
+
+    fn name(self) -> &'static str {
+        Self::NAMES.iter().find(|(.., c)| *c == self).unwrap().0
+    }
+
+    fn from_flag(flag: char) -> Option<Self> {
+        Self::NAMES
+            .iter()
+            .find(|(_, f, _)| *f == flag)
+            .map(|(.., c)| *c)
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Words(words) => write!(f, "-W {}", quote_word(words)),
+            Self::Function(name) => write!(f, "-F {name}"),
+            Self::Command(command) => write!(f, "-C {}", quote_word(command)),
+            Self::Class(class) => {
+                let (_, flag, _) = Class::NAMES.iter().find(|(.., c)| c == class).unwrap();
+                write!(f, "-{flag}")
+            }
+        }
+    }
+}
+
+/// The word before the cursor, as far as it was typed.
+struct Word<'a> {
+    range: Range<usize>,
+    raw: &'a str,
+    /// The words of the command before this one.
+    words: Vec<&'a str>,
+    /// The quote the word is in at the cursor, if any.
+    quote: Option<char>,
+    /// Whether the word is the file of a redirection.
+    redirect: bool,
+}
+
+impl Word<'_> {
+    /// Whether the word is in the place of a command.
+    fn command(&self) -> bool {
+        self.words.is_empty() && !self.redirect
+    }
+}
+
+/// Finds the word before the cursor, which starts after the last blank or operator that is
+/// not quoted.
+fn word(line: &str, cursor: usize) -> Word<'_> {
+    let mut start = 0;
+    let mut words = Vec::new();
+    let mut redirect = false;
+    let mut quote = None;
+    let mut chars = line[..cursor].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match (c, quote) {
+            ('\\', None | Some('"')) => _ = chars.next(),
+            ('\'' | '"', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (c, None) if ";|&(\n".contains(c) => {
+                words.clear();
+                redirect = false;
+                start = i + 1;
+            }
+            (c, None) if "<>".contains(c) => {
+                redirect = true;
+                start = i + 1;
+            }
+            (c, None) if c.is_whitespace() => {
+                if start < i {
+                    match redirect {
+                        true => redirect = false,
+                        false => words.push(&line[start..i]),
+                    }
+                }
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+    Word {
+        range: start..cursor,
+        raw: &line[start..cursor],
+        words,
+        quote,
+        redirect,
+    }
+}
+
+impl Shell {
+    /// Completes the word before `cursor` in `line`. Returns what the completions replace.
+    pub fn completions(
+        &mut self,
+        line: &str,
+        cursor: usize,
+    ) -> Result<(Range<usize>, Vec<Completion>)> {
+        let word = word(line, cursor);
+        let sources = if word.command() && !word.raw.contains('/') {
+            match word.raw.is_empty() {
+                true => Vec::new(),
+                false => vec![Source::Class(Class::Builtin), Source::Class(Class::Command)],
+            }
+        } else if word.command() || word.redirect {
+            vec![Source::Class(Class::File)]
+        } else {
+            let command = unquote(word.words[0]);
+            self.spec(&command)
+                .unwrap_or(vec![Source::Class(Class::File)])
+        };
+        let mut completions = Vec::new();
+        for source in &sources {
+            completions.extend(self.source(source, &word, line)?);
+        }
+        completions.sort_by(|a, b| a.text.cmp(&b.text));
+        completions.dedup_by(|a, b| a.text == b.text);
+        Ok((word.range, completions))
+    }
+
+    /// The completion spec of a command, read from its file if it has none yet.
+    fn spec(&mut self, command: &str) -> Option<Vec<Source>> {
+        let name = Path::new(command)
+            .file_name()?
+            .to_string_lossy()
+            .into_owned();
+        if !self.specs.specs.contains_key(&name) && self.specs.loaded.insert(name.clone()) {
+            if let Ok(source) = fs::read_to_string(self.completions_dir().join(&name)) {
+                let status = self.status;
+                // a broken spec file is no reason to fail completing
+                if let Ok(list) = parser::parse(&source) {
+                    _ = list.run(self);
+                }
+                self.status = status;
+            }
+        }
+        self.specs.specs.get(&name).cloned()
+    }
+
+    fn completions_dir(&self) -> PathBuf {
+        let config = match self.var("XDG_CONFIG_HOME") {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => expand_tilde("~/.config"),
+        };
+        config.join("shell/completions")
+    }
+
+    fn source(&mut self, source: &Source, word: &Word, line: &str) -> Result<Vec<Completion>> {
+        let prefix = unquote(word.raw);
+        let names: Vec<String> = match source {
+            Source::Class(Class::File) => return Ok(paths(word, false)),
+            Source::Class(Class::Directory) => return Ok(paths(word, true)),
+            Source::Class(Class::Builtin) => BUILTINS.iter().map(|name| name.to_string()).collect(),
+            Source::Class(Class::Command) => self.commands(),
+            Source::Class(Class::Variable) => {
+                let vars = self.vars.sorted();
+                vars.into_iter().map(|(name, _)| name.to_string()).collect()
+            }
+            Source::Words(words) => words.split_whitespace().map(String::from).collect(),
+            Source::Function(command) | Source::Command(command) => {
+                self.run_completer(command, word, &prefix, line)
+            }
+        };
+        let description = match source {
+            Source::Class(class) => Some(class.name()),
+            _ => None,
+        };
+        // a quote the word starts with is kept, and closed after the name
+        let quote = word.raw.chars().next().filter(|c| *c == '\'' || *c == '"');
+        Ok(names
+            .into_iter()
+            .filter(|name| !name.is_empty() && name.starts_with(&prefix))
+            .map(|name| {
+                let mut text: String = quote.into_iter().collect();
+                text += &escape(&name, word.quote);
+                text.extend(word.quote);
+                text.push(' ');
+                Completion {
+                    text,
+                    display: name,
+                    description,
+                }
+            })
+            .collect())
+    }
+
+    /// The commands on `PATH`.
+    fn commands(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for dir in self.var("PATH").unwrap_or_default().split(':') {
+            let Ok(entries) = fs::read_dir(dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                names.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        names
+    }
+
+    /// Runs the function or command of `-F` or `-C` and returns the lines it prints, none if
+    /// it fails or cannot be run.
+    fn run_completer(
+        &mut self,
+        command: &str,
+        word: &Word,
+        prefix: &str,
+        line: &str,
+    ) -> Vec<String> {
+        let previous = word
+            .words
+            .last()
+            .map(|word| unquote(word))
+            .unwrap_or_default();
+        let source = format!(
+            "COMP_LINE={} COMP_POINT={} COMP_CWORD={} {command} {} {} {}",
+            quote_word(line),
+            word.range.end,
+            word.words.len(),
+            quote_word(&unquote(word.words[0])),
+            quote_word(prefix),
+            quote_word(&previous),
+        );
+        // completing leaves `$?` as it was
+        let status = (self.status, self.last_substitution);
+        let output = self.substitute(&source);
+        let failed = self.status != 0;
+        (self.status, self.last_substitution) = status;
+        match output {
+            Ok(output) if !failed => output.lines().map(String::from).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The `complete` builtin. Sets the spec of the commands named, prints the specs with
+    /// `-p` or without options and removes them with `-r`.
+    pub fn complete(&mut self, args: &[String]) -> Result<String> {
+        let mut sources = Vec::new();
+        let mut print = false;
+        let mut remove = false;
+        let mut names = Vec::new();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-W" => sources.push(Source::Words(value(&mut args, arg)?)),
+                "-F" => sources.push(Source::Function(value(&mut args, arg)?)),
+                "-C" => sources.push(Source::Command(value(&mut args, arg)?)),
+                "-A" => {
+                    let name = value(&mut args, arg)?;
+                    let class = Class::from_name(&name).ok_or_else(|| {
+                        ShellError::Param("complete".into(), format!("{name}: invalid action name"))
+                    })?;
+                    sources.push(Source::Class(class));
+                }
+                "--" => {
+                    names.extend(args.cloned());
+                    break;
+                }
+                _ if arg.len() > 1 && arg.starts_with('-') => {
+                    for flag in arg[1..].chars() {
+                        match flag {
+                            'p' => print = true,
+                            'r' => remove = true,
+                            _ => {
+                                let class = Class::from_flag(flag).ok_or_else(|| {
+                                    ShellError::InvalidOption("complete", format!("-{flag}"))
+                                })?;
+                                sources.push(Source::Class(class));
+                            }
+                        }
+                    }
+                }
+                _ => names.push(arg.clone()),
+            }
+        }
+        let missing = |name: &str| {
+            ShellError::Param(
+                "complete".into(),
+                format!("{name}: no completion specification"),
+            )
+        };
+        if remove {
+            if names.is_empty() {
+                self.specs.specs.clear();
+            }
+            for name in &names {
+                self.specs.specs.remove(name).ok_or_else(|| missing(name))?;
+            }
+            return Ok(String::new());
+        }
+        if print || sources.is_empty() {
+            if names.is_empty() {
+                names = self.specs.specs.keys().cloned().collect();
+                names.sort();
+            }
+            let mut listing = String::new();
+            for name in &names {
+                let sources = self.specs.specs.get(name).ok_or_else(|| missing(name))?;
+                listing += "complete ";
+                for source in sources {
+                    listing += &format!("{source} ");
+                }
+                listing += &format!("{}\n", quote_word(name));
+            }
+            return Ok(listing);
+        }
+        for name in names {
+            self.specs.specs.insert(name, sources.clone());
+        }
+        Ok(String::new())
+    }
+}
+
+/// The argument of an option of `complete`.
+fn value(args: &mut slice::Iter<String>, option: &str) -> Result<String> {
+    args.next().cloned().ok_or_else(|| {
+        ShellError::Param(
+            "complete".into(),
+            format!("{option}: option requires an argument"),
+        )
+    })
+}
+
+/// The files, or only the directories, whose paths start with the word.
+fn paths(word: &Word, directories: bool) -> Vec<Completion> {
+    let value = unquote(word.raw);
+    if value == "~" {
+        return vec![Completion {
+            text: "~/".into(),
+            display: "~/".into(),
+            description: Some("directory"),
+        }];
+    }
+    let (dir, prefix) = match value.rfind('/') {
+        Some(i) => (&value[..=i], &value[i + 1..]),
+        None => ("", value.as_str()),
+    };
+    // the directory is kept as it was typed, and so is a quote the name starts with
+    let typed = match word.raw.rfind('/') {
+        Some(i) => &word.raw[..=i],
+        None if word.raw.starts_with(['\'', '"']) => &word.raw[..1],
+        None => "",
+    };
+    // a quoted `~` is not expanded when the command runs, so it is not here either
+    let path = match dir {
+        "" => ".".into(),
+        _ if word.raw.starts_with('~') => expand_tilde(dir),
+        _ => dir.into(),
+    };
+    let Ok(entries) = fs::read_dir(path) else {
+        return Vec::new();
+    };
+    let mut entries: Vec<(String, bool)> = entries
+        .flatten()
+        .map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            // links to directories count as directories
+            let is_dir = entry.path().is_dir();
+            (name, is_dir)
+        })
+        .filter(|(name, is_dir)| name.starts_with(prefix) && (*is_dir || !directories))
+        .filter(|(name, _)| prefix.starts_with('.') || !name.starts_with('.'))
+        .collect();
+    entries.sort();
+    entries
+        .into_iter()
+        .map(|(name, is_dir)| {
+            let mut text = format!("{typed}{}", escape(&name, word.quote));
+            match (is_dir, word.quote) {
+                (true, _) => text.push('/'),
+                (false, Some(quote)) => text.extend([quote, ' ']),
+                (false, None) => text.push(' '),
+            }
+            // only directories are told apart, plain files are the rule
+            let (display, description) = match is_dir {
+                true => (format!("{name}/"), Some(Class::Directory.name())),
+                false => (name, None),
+            };
+            Completion {
+                text,
+                display,
+                description,
+            }
+        })
+        .collect()
+}
+
+/// The value of a word as far as it was typed, with its quotes and backslashes removed.
+fn unquote(raw: &str) -> String {
+    let mut value = String::new();
+    let mut quote = None;
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', None | Some('"')) => value.extend(chars.next()),
+            ('\'' | '"', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            _ => value.push(c),
+        }
+    }
+    value
+}
+
+/// Writes a name so that it is read back as it is, in the quote the word is in.
+fn escape(name: &str, quote: Option<char>) -> String {
+    let special = match quote {
+        Some('\'') => return name.replace('\'', "'\\''"),
+        Some(_) => "\"\\$`",
+        None => " \t\n'\"\\$`&|;<>()*?[]#~!{}",
+    };
+    let mut escaped = String::new();
+    for c in name.chars() {
+        if special.contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// The longest start that all completions share.
+pub fn common_prefix(completions: &[Completion]) -> &str {
+    let Some((first, rest)) = completions.split_first() else {
+        return "";
+    };
+    let mut common = first.text.as_str();
+    for completion in rest {
+        let len = common
+            .char_indices()
+            .zip(completion.text.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(common.len().min(completion.text.len()), |((i, _), _)| i);
+        common = &common[..len];
+    }
+    common
+}
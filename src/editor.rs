@@ -0,0 +1,431 @@
+//! The line editor of the interactive shell: a buffer with a cursor that can move anywhere in
+//! the line. Redrawing handles any prompt and lines that wrap past the terminal's width.
+//!
+//! The keys are those of emacs: Ctrl-A and Ctrl-E go to the start and the end, Ctrl-B and
+//! Ctrl-F move by a character and Alt-B and Alt-F by a word. Ctrl-W, Alt-D, Ctrl-K and Ctrl-U
+//! kill the word before the cursor, the word after it, the rest of the line and the start of
+//! the line into a kill ring, where Ctrl-Y yanks the last kill back and Alt-Y right after it
+//! replaces it with the kill before. Ctrl-T transposes characters and Ctrl-_ undoes.
+//! `set -o vi` switches to the keys of vi instead, see [`vi`].
+
+mod vi;
+
+use std::{
+    io::{self, Write},
+    ops::Range,
+};
+
+use termion::event::Key;
+
+use vi::Vi;
+
+#[derive(Debug, Default)]
+pub struct Editor {
+    prompt: String,
+    buffer: String,
+    /// Byte offset of the cursor in `buffer`, always on a character boundary.
+    cursor: usize,
+    /// The row of the terminal cursor below the row the prompt starts on, as of the last
+    /// redraw, which is where the next one has to start from.
+    row: usize,
+    /// The column of the terminal cursor as of the last redraw.
+    column: usize,
+    /// Part of the buffer shown in reverse video, like the match of a history search.
+    highlight: Option<Range<usize>>,
+    /// Text shown under the line, like a menu of completions.
+    below: String,
+    /// Killed text, the latest last. It is kept from one line to the next.
+    kills: Vec<String>,
+    /// The lines and cursors to go back to on undo, the latest last.
+    undo: Vec<(String, usize)>,
+    /// What the previous key did, which decides whether a kill joins the one before, what
+    /// Alt-Y replaces and whether typing joins the undo step before.
+    last: Last,
+    /// The state of the vi keymap, `None` with the emacs one.
+    vi: Option<Vi>,
+}
+
+/// What the shell has to do about a key.
+pub enum Action {
+    /// The key edited the line or moved the cursor, which needs a redraw.
+    Edited,
+    /// The key is one for the shell, like Enter or Up.
+    Pass(Key),
+    /// Edit the line in `$VISUAL` or `$EDITOR` and run it, `v` in vi.
+    Edit,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+enum Last {
+    #[default]
+    Other,
+    Insert,
+    Kill,
+    /// Text yanked from the kill ring, with the index of the kill it came from.
+    Yank(Range<usize>, usize),
+}
+
+impl Editor {
+    /// Starts editing an empty line after `prompt`. The terminal cursor has to be at the
+    /// start of a line.
+    pub fn start(&mut self, prompt: String) {
+        self.prompt = prompt;
+        self.buffer.clear();
+        self.cursor = 0;
+        self.row = 0;
+        self.column = 0;
+        self.highlight = None;
+        self.below.clear();
+        self.undo.clear();
+        self.last = Last::Other;
+        if let Some(vi) = &mut self.vi {
+            vi.start();
+        }
+    }
+
+    /// Switches between the vi and the emacs keymap.
+    pub fn set_vi(&mut self, on: bool) {
+        if on != self.vi.is_some() {
+            self.vi = on.then(Vi::default);
+        }
+    }
+
+    pub fn line(&self) -> &str {
+        &self.buffer
+    }
+
+    /// Replaces the line, with the cursor at its end.
+    pub fn set_line(&mut self, line: String) {
+        self.buffer = line;
+        self.cursor = self.buffer.len();
+        self.highlight = None;
+        if self.vi.as_ref().is_some_and(Vi::normal) {
+            self.clamp();
+        }
+    }
+
+    /// Replaces part of the line, with the cursor after the new text.
+    pub fn replace(&mut self, range: Range<usize>, text: &str) {
+        self.save();
+        self.buffer.replace_range(range.clone(), text);
+        self.cursor = range.start + text.len();
+        self.highlight = None;
+    }
+
+    pub fn prompt(&self) -> &str {
+        &self.prompt
+    }
+
+    /// Replaces the prompt, which takes effect on the next redraw.
+    pub fn set_prompt(&mut self, prompt: String) {
+        self.prompt = prompt;
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    /// Moves the cursor to a byte offset in the line.
+    pub fn set_cursor(&mut self, cursor: usize) {
+        self.cursor = cursor.min(self.buffer.len());
+    }
+
+    pub fn set_highlight(&mut self, highlight: Option<Range<usize>>) {
+        self.highlight = highlight;
+    }
+
+    /// Replaces the text under the line, which takes effect on the next redraw.
+    pub fn set_below(&mut self, below: String) {
+        self.below = below;
+    }
+
+    /// Applies an editing key.
+    pub fn key(&mut self, key: &Key) -> Action {
+        match self.vi.take() {
+            Some(mut vi) => {
+                let action = vi.key(self, key);
+                self.vi = Some(vi);
+                action
+            }
+            None if self.emacs(key) => Action::Edited,
+            None => Action::Pass(*key),
+        }
+    }
+
+    /// Applies a key of the emacs keymap and returns whether it was one.
+    fn emacs(&mut self, key: &Key) -> bool {
+        let last = std::mem::take(&mut self.last);
+        match key {
+            Key::Left | Key::Ctrl('b') => self.cursor = self.prev(self.cursor),
+            Key::Right | Key::Ctrl('f') => self.cursor = self.next(self.cursor),
+            Key::Home | Key::Ctrl('a') => self.cursor = 0,
+            Key::End | Key::Ctrl('e') => self.cursor = self.buffer.len(),
+            Key::Alt('b') => self.cursor = self.word_start(self.cursor),
+            Key::Alt('f') => self.cursor = self.word_end(self.cursor),
+            Key::Backspace if self.cursor > 0 => {
+                self.save();
+                let start = self.prev(self.cursor);
+                self.buffer.drain(start..self.cursor);
+                self.cursor = start;
+            }
+            Key::Delete if self.cursor < self.buffer.len() => {
+                self.save();
+                let end = self.next(self.cursor);
+                self.buffer.drain(self.cursor..end);
+            }
+            Key::Backspace | Key::Delete => {}
+            Key::Ctrl('w') => {
+                // a word up to whitespace, unlike Alt-B
+                let before = self.buffer[..self.cursor].trim_end_matches(char::is_whitespace);
+                let start = before
+                    .rfind(char::is_whitespace)
+                    .map_or(0, |i| self.next(i));
+                self.kill(start..self.cursor, true, &last);
+            }
+            Key::Alt('d') => self.kill(self.cursor..self.word_end(self.cursor), false, &last),
+            Key::Ctrl('k') => self.kill(self.cursor..self.buffer.len(), false, &last),
+            Key::Ctrl('u') => self.kill(0..self.cursor, true, &last),
+            Key::Ctrl('y') => {
+                if let Some(text) = self.kills.last().cloned() {
+                    self.save();
+                    self.yank(&text, self.kills.len() - 1);
+                }
+            }
+            Key::Alt('y') => {
+                // only right after a yank, and it stays one
+                let Last::Yank(range, index) = last else {
+                    return true;
+                };
+                let index = index.checked_sub(1).unwrap_or(self.kills.len() - 1);
+                let text = self.kills[index].clone();
+                self.buffer.drain(range.clone());
+                self.cursor = range.start;
+                self.yank(&text, index);
+            }
+            Key::Ctrl('t') => self.transpose(),
+            Key::Ctrl('7') => self.undo(),
+            Key::Char(c) if !c.is_control() => {
+                // a run of typing is undone at once
+                if last != Last::Insert {
+                    self.save();
+                }
+                self.buffer.insert(self.cursor, *c);
+                self.cursor += c.len_utf8();
+                self.last = Last::Insert;
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// Keeps the line as it is for undo.
+    fn save(&mut self) {
+        self.undo.push((self.buffer.clone(), self.cursor));
+    }
+
+    fn undo(&mut self) {
+        if let Some((buffer, cursor)) = self.undo.pop() {
+            self.buffer = buffer;
+            self.cursor = cursor;
+        }
+    }
+
+    /// Moves the cursor from the end of the line onto its last character, as vi's normal mode
+    /// has it always on a character.
+    fn clamp(&mut self) {
+        if self.cursor == self.buffer.len() {
+            self.cursor = self.prev(self.cursor);
+        }
+    }
+
+    /// Removes part of the line into the kill ring. Kills in a row make up one kill, with
+    /// text killed backwards going in front.
+    fn kill(&mut self, range: Range<usize>, backward: bool, last: &Last) {
+        if !range.is_empty() {
+            self.save();
+            let text: String = self.buffer.drain(range.clone()).collect();
+            self.cursor = range.start;
+            match self.kills.last_mut() {
+                Some(kill) if *last == Last::Kill && backward => kill.insert_str(0, &text),
+                Some(kill) if *last == Last::Kill => kill.push_str(&text),
+                _ => self.kills.push(text),
+            }
+        }
+        self.last = Last::Kill;
+    }
+
+    /// Inserts text from the kill ring at the cursor.
+    fn yank(&mut self, text: &str, index: usize) {
+        let start = self.cursor;
+        self.buffer.insert_str(start, text);
+        self.cursor += text.len();
+        self.last = Last::Yank(start..self.cursor, index);
+    }
+
+    /// Swaps the characters before and at the cursor and moves past both, or the last two
+    /// at the end of the line.
+    fn transpose(&mut self) {
+        if self.cursor == 0 || self.buffer.chars().nth(1).is_none() {
+            return;
+        }
+        if self.cursor == self.buffer.len() {
+            self.cursor = self.prev(self.cursor);
+        }
+        self.save();
+        let start = self.prev(self.cursor);
+        let end = self.next(self.cursor);
+        let c = self.buffer.remove(start);
+        self.buffer.insert(end - c.len_utf8(), c);
+        self.cursor = end;
+    }
+
+    /// The start of the word before `i`, where words are letters and digits.
+    fn word_start(&self, i: usize) -> usize {
+        let before = self.buffer[..i].trim_end_matches(|c: char| !c.is_alphanumeric());
+        before.trim_end_matches(char::is_alphanumeric).len()
+    }
+
+    /// The end of the word after `i`.
+    fn word_end(&self, i: usize) -> usize {
+        let after = self.buffer[i..].trim_start_matches(|c: char| !c.is_alphanumeric());
+        let rest = after.trim_start_matches(char::is_alphanumeric);
+        self.buffer.len() - rest.len()
+    }
+
+    /// The offset of the character before `i`.
+    fn prev(&self, i: usize) -> usize {
+        self.buffer[..i]
+            .char_indices()
+            .next_back()
+            .map_or(0, |(i, _)| i)
+    }
+
+    /// The offset of the character after the one at `i`.
+    fn next(&self, i: usize) -> usize {
+        self.buffer[i..]
+            .chars()
+            .next()
+            .map_or(i, |c| i + c.len_utf8())
+    }
+
+    /// Draws the prompt and the line over the previous drawing, leaving the terminal cursor
+    /// at the editor's cursor.
+    pub fn redraw(&mut self, out: &mut impl Write) -> io::Result<()> {
+        let (columns, _) = size();
+        if self.row > 0 {
+            write!(out, "\x1b[{}A", self.row)?;
+        }
+        // clears everything below as well, which a longer line may have wrapped into
+        let buffer = match &self.highlight {
+            Some(range) => format!(
+                "{}\x1b[7m{}\x1b[27m{}",
+                &self.buffer[..range.start],
+                &self.buffer[range.clone()],
+                &self.buffer[range.end..]
+            ),
+            None => self.buffer.clone(),
+        };
+        let buffer = buffer.replace('\n', "\r\n");
+        // vi shows its mode in front of the prompt
+        let prompt = match &self.vi {
+            Some(vi) => format!("{}{}", vi.indicator(), self.prompt),
+            None => self.prompt.clone(),
+        };
+        write!(out, "\r\x1b[J{prompt}{buffer}")?;
+        let prompt = position(&prompt, (0, 0), columns);
+        let mut end = position(&self.buffer, prompt, columns);
+        let mut cursor = position(&self.buffer[..self.cursor], prompt, columns);
+        // a line that fills its last row leaves the terminal cursor on that row
+        let filled = end.1 == columns;
+        if filled {
+            write!(out, "\r\n")?;
+            end = (end.0 + 1, 0);
+        }
+        if !self.below.is_empty() {
+            if !filled {
+                write!(out, "\r\n")?;
+                end = (end.0 + 1, 0);
+            }
+            write!(out, "{}", self.below.replace('\n', "\r\n"))?;
+            end = position(&self.below, end, columns);
+            if end.1 == columns {
+                write!(out, "\r\n")?;
+                end = (end.0 + 1, 0);
+            }
+        }
+        if cursor.1 == columns {
+            cursor = (cursor.0 + 1, 0);
+        }
+        if end.0 > cursor.0 {
+            write!(out, "\x1b[{}A", end.0 - cursor.0)?;
+        }
+        write!(out, "\r")?;
+        if cursor.1 > 0 {
+            write!(out, "\x1b[{}C", cursor.1)?;
+        }
+        (self.row, self.column) = cursor;
+        out.flush()
+    }
+
+    /// Moves the terminal cursor past the end of the line, onto a fresh line below it.
+    pub fn finish(&mut self, out: &mut impl Write) -> io::Result<()> {
+        self.cursor = self.buffer.len();
+        self.below.clear();
+        self.redraw(out)?;
+        // the line may already have wrapped onto a fresh row
+        if self.column > 0 || self.row == 0 {
+            write!(out, "\r\n")?;
+        }
+        self.row = 0;
+        out.flush()
+    }
+
+    /// Leaves the line unfinished for Ctrl-C, marking its end with `^C`.
+    pub fn cancel(&mut self, out: &mut impl Write) -> io::Result<()> {
+        self.cursor = self.buffer.len();
+        self.below.clear();
+        self.redraw(out)?;
+        write!(out, "^C\r\n")?;
+        self.row = 0;
+        out.flush()
+    }
+}
+
+/// The columns and rows of the terminal, 80 by 24 if they cannot be told.
+pub fn size() -> (usize, usize) {
+    match termion::terminal_size() {
+        Ok((columns, rows)) if columns > 0 && rows > 0 => (columns.into(), rows.into()),
+        _ => (80, 24),
+    }
+}
+
+/// Where the terminal cursor ends up after writing `text` from the row and column `from`,
+/// with lines wrapping at `columns`. Escape sequences like colors take no room. The column
+/// is `columns` when a row was just filled, as the terminal only wraps at the next character.
+fn position(text: &str, from: (usize, usize), columns: usize) -> (usize, usize) {
+    let (mut row, mut column) = from;
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => (row, column) = (row + 1, 0),
+            // a control sequence runs up to its final letter
+            '\x1b' => {
+                if chars.next() == Some('[') {
+                    for c in chars.by_ref() {
+                        if c.is_ascii_alphabetic() {
+                            break;
+                        }
+                    }
+                }
+            }
+            c if c.is_control() => {}
+            _ => {
+                if column == columns {
+                    (row, column) = (row + 1, 0);
+                }
+                column += 1;
+            }
+        }
+    }
+    (row, column)
+}
@@ -0,0 +1,442 @@
+//! The keys of vi. Each line starts in insert mode, which takes the keys of emacs but for
+//! Esc, which goes to normal mode. There a command is a motion (`h l w b e W B E 0 ^ $` and
+//! `f t F T` with a character), an operator (`d c y`) with a motion, or one of
+//! `i a I A x X D C s S p P r u . v j k`, any of them after a count. An operator typed twice,
+//! like `dd`, applies to the whole line. `.` repeats the last change, text typed in insert
+//! mode included, and `v` edits the line in `$EDITOR`.
+
+use std::{iter::Peekable, str::Chars};
+
+use termion::event::Key;
+
+use super::{Action, Editor, Last};
+
+/// The keys that are motions, which operators take.
+const MOTIONS: &str = "hlwbeWBE0^$fFtT";
+
+/// The keys that are commands of their own.
+const COMMANDS: &str = "iaIAxXDCsSpPru.vjk";
+
+/// The most copies `p` and `P` put, so that a huge count cannot use up the memory.
+const MAX_PUT: usize = 1000;
+
+#[derive(Debug, Default)]
+pub struct Vi {
+    normal: bool,
+    /// The keys of the normal mode command typed so far.
+    pending: String,
+    /// The keys of the last command that changed the line and the text it had typed
+    /// afterwards, which `.` repeats.
+    change: Option<(String, String)>,
+    /// The keys of the command that went to insert mode and the text typed since.
+    inserting: Option<(String, String)>,
+}
+
+struct Command {
+    count: Option<usize>,
+    operator: Option<char>,
+    key: char,
+    /// The character of `f`, `t` or `r`.
+    arg: Option<char>,
+}
+
+impl Command {
+    fn changes(&self) -> bool {
+        matches!(self.operator, Some('d' | 'c'))
+            || (self.operator.is_none() && "iaIAxXDCsSpPr".contains(self.key))
+    }
+}
+
+enum Parse {
+    Incomplete,
+    Invalid,
+    Done(Command),
+}
+
+/// Takes the count in front of a command or a motion, if any.
+fn count(chars: &mut Peekable<Chars>) -> Option<usize> {
+    let mut count = None;
+    // a leading 0 is the motion
+    while let Some(c) = chars.next_if(|c| c.is_ascii_digit() && (*c != '0' || count.is_some())) {
+        let digit = c.to_digit(10).unwrap_or_default() as usize;
+        count = Some(
+            count
+                .unwrap_or(0usize)
+                .saturating_mul(10)
+                .saturating_add(digit),
+        );
+    }
+    count
+}
+
+fn parse(keys: &str) -> Parse {
+    let mut chars = keys.chars().peekable();
+    let first = count(&mut chars);
+    let mut second = None;
+    let mut operator = None;
+    let Some(mut key) = chars.next() else {
+        return Parse::Incomplete;
+    };
+    if "dcy".contains(key) {
+        operator = Some(key);
+        second = count(&mut chars);
+        let Some(motion) = chars.next() else {
+            return Parse::Incomplete;
+        };
+        if motion != key && !MOTIONS.contains(motion) {
+            return Parse::Invalid;
+        }
+        key = motion;
+    } else if !MOTIONS.contains(key) && !COMMANDS.contains(key) {
+        return Parse::Invalid;
+    }
+    let mut arg = None;
+    if "fFtTr".contains(key) {
+        let Some(c) = chars.next() else {
+            return Parse::Incomplete;
+        };
+        arg = Some(c);
+    }
+    let count = match (first, second) {
+        (None, None) => None,
+        (first, second) => Some(first.unwrap_or(1).saturating_mul(second.unwrap_or(1))),
+    };
+    Parse::Done(Command {
+        count,
+        operator,
+        key,
+        arg,
+    })
+}
+
+impl Vi {
+    /// Goes back to insert mode for a new line.
+    pub fn start(&mut self) {
+        self.normal = false;
+        self.pending.clear();
+        self.inserting = None;
+    }
+
+    pub fn normal(&self) -> bool {
+        self.normal
+    }
+
+    /// Shows the mode in front of the prompt.
+    pub fn indicator(&self) -> &'static str {
+        match self.normal {
+            true => "(cmd)",
+            false => "(ins)",
+        }
+    }
+
+    pub fn key(&mut self, editor: &mut Editor, key: &Key) -> Action {
+        if !self.normal {
+            if *key == Key::Esc {
+                self.normal_mode(editor);
+                return Action::Edited;
+            }
+            if !editor.emacs(key) {
+                return Action::Pass(*key);
+            }
+            if let Some((_, text)) = &mut self.inserting {
+                match key {
+                    Key::Char(c) => text.push(*c),
+                    Key::Backspace => _ = text.pop(),
+                    _ => {}
+                }
+            }
+            return Action::Edited;
+        }
+        let c = match key {
+            Key::Char(c) if !c.is_control() => *c,
+            Key::Backspace => 'h',
+            Key::Esc => {
+                self.pending.clear();
+                return Action::Edited;
+            }
+            Key::Left | Key::Right | Key::Home | Key::End => {
+                editor.emacs(key);
+                editor.clamp();
+                return Action::Edited;
+            }
+            _ => {
+                self.pending.clear();
+                return Action::Pass(*key);
+            }
+        };
+        self.pending.push(c);
+        let command = match parse(&self.pending) {
+            Parse::Incomplete => return Action::Edited,
+            Parse::Invalid => {
+                self.pending.clear();
+                return Action::Edited;
+            }
+            Parse::Done(command) => command,
+        };
+        let keys = std::mem::take(&mut self.pending);
+        let action = self.run(editor, &command);
+        if command.changes() {
+            match self.normal {
+                true => self.change = Some((keys, String::new())),
+                false => self.inserting = Some((keys, String::new())),
+            }
+        }
+        if self.normal {
+            editor.clamp();
+        }
+        action
+    }
+
+    fn normal_mode(&mut self, editor: &mut Editor) {
+        self.normal = true;
+        if let Some(change) = self.inserting.take() {
+            self.change = Some(change);
+        }
+        editor.cursor = editor.prev(editor.cursor);
+        editor.last = Last::Other;
+    }
+
+    /// Goes to insert mode at `cursor`, where the text typed is one step to undo.
+    fn insert_mode(&mut self, editor: &mut Editor, cursor: usize) {
+        self.normal = false;
+        editor.cursor = cursor;
+        editor.last = Last::Insert;
+    }
+
+    fn run(&mut self, editor: &mut Editor, command: &Command) -> Action {
+        // no motion or change goes further than one step per character of the line, which
+        // keeps a huge count from hanging the shell
+        let count = command.count.unwrap_or(1).min(editor.buffer.len() + 1);
+        // the commands that are short for an operator and a motion
+        let (operator, key) = match (command.operator, command.key) {
+            (None, 'x') => (Some('d'), 'l'),
+            (None, 'X') => (Some('d'), 'h'),
+            (None, 'D') => (Some('d'), '$'),
+            (None, 'C') => (Some('c'), '$'),
+            (None, 's') => (Some('c'), 'l'),
+            (None, 'S') => (Some('c'), 'c'),
+            keys => keys,
+        };
+        if let Some(operator) = operator {
+            self.operate(editor, operator, key, command.arg, count);
+            return Action::Edited;
+        }
+        match key {
+            'i' | 'a' | 'I' | 'A' => {
+                let cursor = match key {
+                    'i' => editor.cursor,
+                    'a' => editor.next(editor.cursor),
+                    'I' => editor.buffer.len() - editor.buffer.trim_start().len(),
+                    _ => editor.buffer.len(),
+                };
+                editor.save();
+                self.insert_mode(editor, cursor);
+            }
+            'p' | 'P' => {
+                let Some(text) = editor.kills.last() else {
+                    return Action::Edited;
+                };
+                let text = text.repeat(command.count.unwrap_or(1).min(MAX_PUT));
+                let at = match key {
+                    'p' => editor.next(editor.cursor),
+                    _ => editor.cursor,
+                };
+                editor.save();
+                editor.buffer.insert_str(at, &text);
+                editor.cursor = editor.prev(at + text.len());
+            }
+            'r' => {
+                let Some(c) = command.arg else {
+                    return Action::Edited;
+                };
+                let end = (0..count).try_fold(editor.cursor, |i, _| {
+                    (i < editor.buffer.len()).then(|| editor.next(i))
+                });
+                if let Some(end) = end {
+                    editor.save();
+                    let text = c.to_string().repeat(count);
+                    editor.buffer.replace_range(editor.cursor..end, &text);
+                    editor.cursor += text.len() - c.len_utf8();
+                }
+            }
+            'u' => editor.undo(),
+            '.' => self.repeat(editor, command.count),
+            'v' => return Action::Edit,
+            'j' => return Action::Pass(Key::Down),
+            'k' => return Action::Pass(Key::Up),
+            _ => {
+                if let Some((target, _)) = motion(editor, key, command.arg, count) {
+                    editor.cursor = target;
+                }
+            }
+        }
+        Action::Edited
+    }
+
+    /// Applies `d`, `c` or `y` to the text between the cursor and where a motion goes.
+    fn operate(
+        &mut self,
+        editor: &mut Editor,
+        operator: char,
+        key: char,
+        arg: Option<char>,
+        count: usize,
+    ) {
+        let range = if key == operator {
+            0..editor.buffer.len()
+        } else {
+            // `cw` changes up to the end of the word, not to the start of the next one
+            let on_word = editor.buffer[editor.cursor..].starts_with(|c: char| !c.is_whitespace());
+            let key = match key {
+                'w' if operator == 'c' && on_word => 'e',
+                'W' if operator == 'c' && on_word => 'E',
+                key => key,
+            };
+            let Some((target, inclusive)) = motion(editor, key, arg, count) else {
+                return;
+            };
+            let start = target.min(editor.cursor);
+            let end = target.max(editor.cursor);
+            match inclusive {
+                true => start..editor.next(end),
+                false => start..end,
+            }
+        };
+        match operator {
+            'y' => {
+                editor.kills.push(editor.buffer[range.clone()].into());
+                editor.cursor = range.start;
+            }
+            _ if range.is_empty() && operator == 'd' => {}
+            _ => {
+                editor.save();
+                let text: String = editor.buffer.drain(range.clone()).collect();
+                if !text.is_empty() {
+                    editor.kills.push(text);
+                }
+                editor.cursor = range.start;
+                if operator == 'c' {
+                    self.insert_mode(editor, range.start);
+                }
+            }
+        }
+    }
+
+    /// Repeats the last change, `count` times as many if given.
+    fn repeat(&mut self, editor: &mut Editor, count: Option<usize>) {
+        let Some((keys, text)) = self.change.clone() else {
+            return;
+        };
+        let keys = match count {
+            Some(count) => format!(
+                "{count}{}",
+                keys.trim_start_matches(|c: char| c.is_ascii_digit())
+            ),
+            None => keys,
+        };
+        let Parse::Done(command) = parse(&keys) else {
+            return;
+        };
+        self.run(editor, &command);
+        if !self.normal {
+            editor.buffer.insert_str(editor.cursor, &text);
+            editor.cursor += text.len();
+            self.normal_mode(editor);
+        }
+    }
+}
+
+/// Where a motion moves the cursor, and whether an operator takes the character there too.
+fn motion(editor: &Editor, key: char, arg: Option<char>, count: usize) -> Option<(usize, bool)> {
+    let chars: Vec<char> = editor.buffer.chars().collect();
+    let offset = |k: usize| chars[..k].iter().map(|c| c.len_utf8()).sum::<usize>();
+    let at = editor.buffer[..editor.cursor].chars().count();
+    let big = key.is_ascii_uppercase();
+    let repeat = |step: &dyn Fn(usize) -> usize| (0..count).fold(at, |k, _| step(k));
+    let find = |forward: bool| {
+        let arg = arg?;
+        let mut matches = match forward {
+            true => Box::new((at + 1..chars.len()).filter(|&k| chars[k] == arg))
+                as Box<dyn Iterator<Item = usize>>,
+            false => Box::new((0..at).rev().filter(|&k| chars[k] == arg)),
+        };
+        matches.nth(count - 1)
+    };
+    let (k, inclusive) = match key {
+        'h' => (at.saturating_sub(count), false),
+        'l' => (at.saturating_add(count).min(chars.len()), false),
+        '0' => (0, false),
+        '^' => (
+            chars.iter().take_while(|c| c.is_whitespace()).count(),
+            false,
+        ),
+        '$' => (chars.len(), false),
+        'w' | 'W' => (repeat(&|k| next_word(&chars, k, big)), false),
+        'b' | 'B' => (repeat(&|k| prev_word(&chars, k, big)), false),
+        'e' | 'E' => (repeat(&|k| word_end(&chars, k, big)), true),
+        'f' => (find(true)?, true),
+        't' => (find(true)? - 1, true),
+        'F' => (find(false)?, false),
+        'T' => (find(false)? + 1, false),
+        _ => return None,
+    };
+    Some((offset(k), inclusive))
+}
+
+/// Words are runs of letters, digits and underscores, or runs of other characters that are
+/// not blank. For `W`, `B` and `E` they are anything between blanks.
+fn class(c: char, big: bool) -> u8 {
+    if c.is_whitespace() {
+        0
+    } else if big || c.is_alphanumeric() || c == '_' {
+        1
+    } else {
+        2
+    }
+}
+
+/// The start of the word after the one at `k`.
+fn next_word(chars: &[char], mut k: usize, big: bool) -> usize {
+    if let Some(&c) = chars.get(k) {
+        let first = class(c, big);
+        while k < chars.len() && first != 0 && class(chars[k], big) == first {
+            k += 1;
+        }
+    }
+    while k < chars.len() && class(chars[k], big) == 0 {
+        k += 1;
+    }
+    k
+}
+
+/// The last character of the word at or after the one after `k`.
+fn word_end(chars: &[char], mut k: usize, big: bool) -> usize {
+    k += 1;
+    while k < chars.len() && class(chars[k], big) == 0 {
+        k += 1;
+    }
+    let Some(&c) = chars.get(k) else {
+        return chars.len().saturating_sub(1);
+    };
+    let first = class(c, big);
+    while k + 1 < chars.len() && class(chars[k + 1], big) == first {
+        k += 1;
+    }
+    k
+}
+
+/// The start of the word before `k`, or of the one `k` is in.
+fn prev_word(chars: &[char], mut k: usize, big: bool) -> usize {
+    if k == 0 {
+        return 0;
+    }
+    k -= 1;
+    while k > 0 && class(chars[k], big) == 0 {
+        k -= 1;
+    }
+    let first = class(chars[k], big);
+    while k > 0 && class(chars[k - 1], big) == first {
+        k -= 1;
+    }
+    k
+}
@@ -0,0 +1,530 @@
+//! Word expansion: tilde and parameter expansion, command substitution, field splitting,
+//! pathname expansion and quote removal.
+
+use std::{
+    ffi::{CStr, CString},
+    io::{self, Read, Write},
+    mem,
+    os::fd::AsRawFd,
+};
+
+use crate::{
+    fork, glob,
+    lexer::{Param, ParamOp, ReplaceMode, Word, WordPart},
+    parser, pattern, pipe,
+    vars::is_name,
+    Result, Shell, ShellError, Stage,
+};
+
+const DEFAULT_IFS: &str = " \t\n";
+
+/// Collects the fields a word expands to.
+struct Fields {
+    fields: Vec<Field>,
+    current: Field,
+    /// Whether `current` is a field even if it is empty, which quoting makes it.
+    started: bool,
+    /// The characters unquoted expansions are split at, `None` to not split at all.
+    ifs: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Field {
+    text: String,
+    /// The field as a pattern, with its quoted characters escaped.
+    pattern: String,
+    /// Whether an unquoted part of the field has pattern characters, which makes it a
+    /// candidate for pathname expansion.
+    glob: bool,
+}
+
+impl Field {
+    fn push_str(&mut self, text: &str, quoted: bool) {
+        self.text.push_str(text);
+        if quoted {
+            self.pattern.push_str(&pattern::escape(text));
+        } else {
+            self.pattern.push_str(text);
+            self.glob |= pattern::has_meta(text);
+        }
+    }
+}
+
+impl Fields {
+    fn new(ifs: Option<String>) -> Self {
+        Self {
+            fields: Vec::new(),
+            current: Field::default(),
+            started: false,
+            ifs,
+        }
+    }
+
+    /// Adds text as it was written.
+    fn push(&mut self, text: &str, quoted: bool) {
+        self.current.push_str(text, quoted);
+        self.started |= quoted || !text.is_empty();
+    }
+
+    /// Adds the result of an expansion, which is split into fields unless it was quoted.
+    fn push_expansion(&mut self, text: &str, quoted: bool) {
+        let ifs = match self.ifs.take() {
+            Some(ifs) if !quoted && !ifs.is_empty() => ifs,
+            ifs => {
+                self.ifs = ifs;
+                self.current.push_str(text, quoted);
+                self.started |= quoted || !text.is_empty();
+                return;
+            }
+        };
+        let mut chars = text.char_indices().peekable();
+        let mut start = 0;
+        while let Some((i, c)) = chars.next() {
+            if !ifs.contains(c) {
+                continue;
+            }
+            self.current.push_str(&text[start..i], false);
+            self.started |= i > start;
+            // white space around a delimiter that is not white space belongs to it
+            let mut hard = !c.is_whitespace();
+            start = i + c.len_utf8();
+            while let Some((i, n)) =
+                chars.next_if(|(_, n)| ifs.contains(*n) && (n.is_whitespace() || !hard))
+            {
+                hard |= !n.is_whitespace();
+                start = i + n.len_utf8();
+            }
+            if self.started || hard {
+                self.finish();
+            }
+        }
+        self.current.push_str(&text[start..], false);
+        self.started |= start < text.len();
+        self.ifs = Some(ifs);
+    }
+
+    fn finish(&mut self) {
+        self.fields.push(mem::take(&mut self.current));
+        self.started = false;
+    }
+
+    fn into_fields(mut self) -> Vec<Field> {
+        if self.started {
+            self.finish();
+        }
+        self.fields
+    }
+
+    /// The fields as text, without pathname expansion.
+    fn into_strings(self) -> Vec<String> {
+        self.into_fields().into_iter().map(|f| f.text).collect()
+    }
+}
+
+impl Shell {
+    /// Expands a word into the fields it stands for. The results of unquoted expansions are
+    /// split at the characters in `IFS`, and a word that ends up empty without having been
+    /// quoted disappears.
+    pub fn expand_word(&mut self, word: &Word) -> Result<Vec<String>> {
+        let ifs = self.param("IFS").unwrap_or(DEFAULT_IFS.into());
+        let mut fields = Fields::new(Some(ifs));
+        self.expand_parts(&word.parts, false, &mut fields)?;
+        if self.options.noglob {
+            return Ok(fields.into_strings());
+        }
+        let mut res = Vec::new();
+        for field in fields.into_fields() {
+            let paths = if field.glob {
+                glob::glob(&field.pattern, self.options.globstar)
+            } else {
+                Vec::new()
+            };
+            // a pattern that matches nothing stays as it is
+            if paths.is_empty() {
+                res.push(field.text);
+            } else {
+                res.extend(paths);
+            }
+        }
+        Ok(res)
+    }
+
+    /// Expands a word into a single string without splitting it, as for redirection targets.
+    pub fn expand_string(&mut self, word: &Word) -> Result<String> {
+        let mut fields = Fields::new(None);
+        self.expand_parts(&word.parts, false, &mut fields)?;
+        Ok(fields.into_strings().concat())
+    }
+
+    /// Expands a word into a pattern, in which quoted characters match literally.
+    fn expand_pattern(&mut self, word: &Word) -> Result<String> {
+        let mut res = String::new();
+        for part in &word.parts {
+            match part {
+                WordPart::Text { text, quoted: true } => res.push_str(&pattern::escape(text)),
+                WordPart::Text { text, .. } => res.push_str(text),
+                WordPart::Param { param, quoted } => {
+                    let mut fields = Fields::new(None);
+                    self.expand_param(param, true, &mut fields)?;
+                    let value = fields.into_strings().concat();
+                    if *quoted {
+                        res.push_str(&pattern::escape(&value));
+                    } else {
+                        res.push_str(&value);
+                    }
+                }
+                WordPart::Command { source, quoted } => {
+                    let output = self.substitute(source)?;
+                    if *quoted {
+                        res.push_str(&pattern::escape(&output));
+                    } else {
+                        res.push_str(&output);
+                    }
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// `quoted` marks all parts as quoted, as for the word inside of a quoted `${...}`.
+    fn expand_parts(
+        &mut self,
+        parts: &[WordPart],
+        quoted: bool,
+        fields: &mut Fields,
+    ) -> Result<()> {
+        let mut parts = parts;
+        if let Some((home, rest)) = self.tilde_prefix(parts).filter(|_| !quoted) {
+            // the home directory is neither split nor matched as a pattern
+            fields.push(&home, true);
+            fields.push(rest, false);
+            parts = &parts[1..];
+        }
+        for part in parts {
+            match part {
+                WordPart::Text { text, quoted: q } => fields.push(text, quoted || *q),
+                WordPart::Param { param, quoted: q } => {
+                    self.expand_param(param, quoted || *q, fields)?
+                }
+                WordPart::Command { source, quoted: q } => {
+                    let output = self.substitute(source)?;
+                    fields.push_expansion(&output, quoted || *q);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The home directory a word starting with an unquoted `~` or `~user` names, and the
+    /// rest of its first part. The prefix ends at the first `/`, so one that runs into
+    /// another part, as in `~"/x"` or `~$USER`, is left as it is.
+    fn tilde_prefix<'a>(&self, parts: &'a [WordPart]) -> Option<(String, &'a str)> {
+        let Some(WordPart::Text {
+            text,
+            quoted: false,
+        }) = parts.first()
+        else {
+            return None;
+        };
+        let rest = text.strip_prefix('~')?;
+        let (user, rest) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None if parts.len() == 1 => (rest, ""),
+            None => return None,
+        };
+        let home = match user {
+            "" => self.var("HOME")?,
+            user => home_of(user)?,
+        };
+        Some((home, rest))
+    }
+
+    fn expand_param(&mut self, param: &Param, quoted: bool, fields: &mut Fields) -> Result<()> {
+        if param.op.is_none() && (param.name == "@" || param.name == "*") {
+            self.expand_positional(param.name == "@", quoted, fields);
+            return Ok(());
+        }
+        let value = self.param(&param.name);
+        let Some(op) = &param.op else {
+            fields.push_expansion(&value.unwrap_or_default(), quoted);
+            return Ok(());
+        };
+        let is_set = |colon: bool| value.as_ref().is_some_and(|v| !colon || !v.is_empty());
+        let value = match op {
+            ParamOp::Length => value.map_or(0, |v| v.chars().count()).to_string(),
+            ParamOp::Default { colon, word } if !is_set(*colon) => {
+                return self.expand_parts(&word.parts, quoted, fields);
+            }
+            ParamOp::Alternate { colon, word } if is_set(*colon) => {
+                return self.expand_parts(&word.parts, quoted, fields);
+            }
+            ParamOp::Alternate { .. } => String::new(),
+            ParamOp::Assign { colon, word } if !is_set(*colon) => {
+                let value = self.expand_string(word)?;
+                if !is_name(&param.name) {
+                    return Err(ShellError::Param(
+                        format!("${}", param.name),
+                        "cannot assign in this way".into(),
+                    ));
+                }
+                self.set_var(&param.name, value.clone())?;
+                value
+            }
+            ParamOp::Error { colon, word } if !is_set(*colon) => {
+                let message = if word.parts.is_empty() {
+                    "parameter null or not set".into()
+                } else {
+                    self.expand_string(word)?
+                };
+                return Err(ShellError::NotSet(param.name.clone(), message));
+            }
+            ParamOp::RemovePrefix { longest, pattern } => {
+                let pattern = self.expand_pattern(pattern)?;
+                remove_prefix(&value.unwrap_or_default(), &pattern, *longest)
+            }
+            ParamOp::RemoveSuffix { longest, pattern } => {
+                let pattern = self.expand_pattern(pattern)?;
+                remove_suffix(&value.unwrap_or_default(), &pattern, *longest)
+            }
+            ParamOp::Replace {
+                mode,
+                pattern,
+                replacement,
+            } => {
+                let pattern = self.expand_pattern(pattern)?;
+                let replacement = self.expand_string(replacement)?;
+                replace(&value.unwrap_or_default(), &pattern, &replacement, *mode)
+            }
+            ParamOp::Default { .. } | ParamOp::Assign { .. } | ParamOp::Error { .. } => {
+                value.unwrap_or_default()
+            }
+        };
+        fields.push_expansion(&value, quoted);
+        Ok(())
+    }
+
+    /// Runs `source` in a forked copy of the shell and returns what it wrote to stdout,
+    /// without trailing newlines. The substitution's exit status becomes `$?`.
+    pub fn substitute(&mut self, source: &str) -> Result<String> {
+        let list = parser::parse(source)?;
+        let (mut read, write) = pipe()?;
+        let pid = fork()?;
+        if pid == 0 {
+            unsafe {
+                libc::dup2(write.as_raw_fd(), 1);
+                libc::close(read.as_raw_fd());
+            }
+            drop(write);
+            self.enter_subshell();
+            let code = match list.run(self) {
+                Ok(code) | Err(ShellError::Exit(code)) => code,
+                Err(e) => {
+                    eprintln!("{e}");
+                    1
+                }
+            };
+            _ = io::stdout().flush();
+            unsafe { libc::_exit(code) }
+        }
+        drop(write);
+        let mut output = Vec::new();
+        let read_result = read.read_to_end(&mut output);
+        let status = Stage::Started(pid).wait()?;
+        read_result?;
+        self.status = status;
+        self.last_substitution = Some(status);
+        let mut output = String::from_utf8_lossy(&output).into_owned();
+        output.truncate(output.trim_end_matches('\n').len());
+        Ok(output)
+    }
+
+    /// Expands `$@` or `$*`. Each positional parameter is a field of its own, except in
+    /// `"$*"`, which joins them with the first character of `IFS`.
+    fn expand_positional(&self, at: bool, quoted: bool, fields: &mut Fields) {
+        if quoted && !at {
+            let ifs = self.param("IFS").unwrap_or(DEFAULT_IFS.into());
+            let separator = ifs.chars().next().map(String::from).unwrap_or_default();
+            fields.push(&self.positional.join(&separator), true);
+            return;
+        }
+        for (i, value) in self.positional.iter().enumerate() {
+            if i > 0 && (quoted || fields.started) {
+                fields.finish();
+            }
+            fields.push_expansion(value, quoted);
+        }
+    }
+
+    /// The value of a variable or special parameter, `None` if it is unset.
+    fn param(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.status.to_string()),
+            "$" => Some(std::process::id().to_string()),
+            "0" => Some(self.arg0.clone()),
+            "#" => Some(self.positional.len().to_string()),
+            "-" => Some(self.options.flags()),
+            "@" | "*" => Some(self.positional.join(" ")),
+            "!" => self.last_background.map(|pid| pid.to_string()),
+            _ if name.starts_with(|c: char| c.is_ascii_digit()) => {
+                let n: usize = name.parse().ok()?;
+                self.positional.get(n.checked_sub(1)?).cloned()
+            }
+            _ => self.var(name),
+        }
+    }
+}
+
+/// The home directory of `user` in the password database.
+fn home_of(user: &str) -> Option<String> {
+    let user = CString::new(user).ok()?;
+    let entry = unsafe { libc::getpwnam(user.as_ptr()) };
+    if entry.is_null() {
+        return None;
+    }
+    let home = unsafe { CStr::from_ptr((*entry).pw_dir) };
+    Some(home.to_string_lossy().into_owned())
+}
+
+/// The positions in `value` that split it on character boundaries, including both ends.
+fn boundaries(value: &str) -> Vec<usize> {
+    value
+        .char_indices()
+        .map(|(i, _)| i)
+        .chain([value.len()])
+        .collect()
+}
+
+fn remove_prefix(value: &str, pattern: &str, longest: bool) -> String {
+    let mut ends = boundaries(value);
+    if longest {
+        ends.reverse();
+    }
+    match ends
+        .into_iter()
+        .find(|end| pattern::matches(pattern, &value[..*end]))
+    {
+        Some(end) => value[end..].into(),
+        None => value.into(),
+    }
+}
+
+fn remove_suffix(value: &str, pattern: &str, longest: bool) -> String {
+    let mut starts = boundaries(value);
+    if !longest {
+        starts.reverse();
+    }
+    match starts
+        .into_iter()
+        .find(|start| pattern::matches(pattern, &value[*start..]))
+    {
+        Some(start) => value[..start].into(),
+        None => value.into(),
+    }
+}
+
+/// Replaces the longest matches of `pattern`. Empty matches are only replaced at the start
+/// or the end that `/#` and `/%` anchor the pattern to.
+fn replace(value: &str, pattern: &str, replacement: &str, mode: ReplaceMode) -> String {
+    let bounds = boundaries(value);
+    let mut res = String::new();
+    let mut last = 0;
+    let mut i = 0;
+    while i < bounds.len() {
+        let start = bounds[i];
+        if mode == ReplaceMode::Prefix && start > 0 {
+            break;
+        }
+        let anchored = matches!(mode, ReplaceMode::Prefix | ReplaceMode::Suffix);
+        let first = if anchored { i } else { i + 1 };
+        let end = bounds[first..].iter().rev().position(|end| {
+            (mode != ReplaceMode::Suffix || *end == value.len())
+                && pattern::matches(pattern, &value[start..*end])
+        });
+        let Some(from_back) = end else {
+            i += 1;
+            continue;
+        };
+        let end_idx = bounds.len() - 1 - from_back;
+        res.push_str(&value[last..start]);
+        res.push_str(replacement);
+        last = bounds[end_idx];
+        if mode != ReplaceMode::All {
+            break;
+        }
+        i = end_idx;
+    }
+    res.push_str(&value[last..]);
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(value: &str, ifs: &str) -> Vec<String> {
+        let mut fields = Fields::new(Some(ifs.into()));
+        fields.push_expansion(value, false);
+        fields.into_strings()
+    }
+
+    #[test]
+    fn field_splitting() {
+        assert_eq!(split("  a \t b\n", DEFAULT_IFS), ["a", "b"]);
+        assert_eq!(split("a::b:", ":"), ["a", "", "b"]);
+        assert_eq!(split(":a", ":"), ["", "a"]);
+        // white space around a delimiter that is not white space belongs to it
+        assert_eq!(split("a : b", " :"), ["a", "b"]);
+        assert_eq!(split("a b", ""), ["a b"]);
+        assert!(split("", DEFAULT_IFS).is_empty());
+    }
+
+    #[test]
+    fn fields_join_the_text_around_them() {
+        let mut fields = Fields::new(Some(DEFAULT_IFS.into()));
+        fields.push("x", false);
+        fields.push_expansion(" a b ", false);
+        fields.push("y", false);
+        fields.push_expansion("c d", true);
+        assert_eq!(fields.into_strings(), ["x", "a", "b", "yc d"]);
+    }
+
+    #[test]
+    fn quoting_keeps_empty_fields() {
+        let mut fields = Fields::new(Some(DEFAULT_IFS.into()));
+        fields.push_expansion("", true);
+        assert_eq!(fields.into_strings(), [""]);
+        let mut fields = Fields::new(Some(DEFAULT_IFS.into()));
+        fields.push_expansion("", false);
+        assert!(fields.into_strings().is_empty());
+    }
+
+    #[test]
+    fn removes_prefixes_and_suffixes() {
+        assert_eq!(remove_prefix("a/b/c", "*/", false), "b/c");
+        assert_eq!(remove_prefix("a/b/c", "*/", true), "c");
+        assert_eq!(remove_prefix("a/b/c", "x*", true), "a/b/c");
+        assert_eq!(remove_suffix("a/b/c", "/*", false), "a/b");
+        assert_eq!(remove_suffix("a/b/c", "/*", true), "a");
+        assert_eq!(remove_suffix("äöü", "?", false), "äö");
+    }
+
+    #[test]
+    fn replaces_longest_matches() {
+        assert_eq!(replace("aaa", "a", "b", ReplaceMode::First), "baa");
+        assert_eq!(replace("aaa", "a", "b", ReplaceMode::All), "bbb");
+        assert_eq!(replace("abcabc", "a*c", "X", ReplaceMode::First), "X");
+        assert_eq!(replace("abab", "ab", "X", ReplaceMode::Prefix), "Xab");
+        assert_eq!(replace("abab", "ab", "X", ReplaceMode::Suffix), "abX");
+        assert_eq!(replace("bab", "a", "X", ReplaceMode::Prefix), "bab");
+        assert_eq!(replace("aba", "b", "X", ReplaceMode::Suffix), "aba");
+    }
+
+    #[test]
+    fn replaces_empty_matches_only_at_anchors() {
+        assert_eq!(replace("abc", "", "X", ReplaceMode::Prefix), "Xabc");
+        assert_eq!(replace("abc", "", "X", ReplaceMode::Suffix), "abcX");
+        assert_eq!(replace("abc", "", "X", ReplaceMode::All), "abc");
+        assert_eq!(replace("abc", "x*", "X", ReplaceMode::First), "abc");
+        assert_eq!(replace("", "", "X", ReplaceMode::Prefix), "X");
+    }
+}
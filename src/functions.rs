@@ -0,0 +1,26 @@
+//! Shell functions, defined with `name() { list; }` and called like commands. A call runs
+//! the body in the shell itself with the arguments as the positional parameters, which are
+//! put back when it returns. Assignments in front of a call stay set after it.
+
+use std::io::{self, Write};
+
+use crate::{ast, redirect, Args, Fds, Result, Shell};
+
+impl Shell {
+    /// Calls a function. Its redirections are installed on the shell's own descriptors while
+    /// the body runs, so that all of its commands write to them.
+    pub fn call(&mut self, body: &ast::Command, args: &Args, fds: &Fds) -> Result<i32> {
+        for (name, value) in &args.env {
+            self.set_var(name, value.clone())?;
+        }
+        io::stdout().flush()?;
+        let saved = redirect::save(fds);
+        redirect::install(fds)?;
+        let positional = std::mem::replace(&mut self.positional, args.args.clone());
+        let status = body.run(self);
+        self.positional = positional;
+        _ = io::stdout().flush();
+        redirect::install(&saved)?;
+        status
+    }
+}
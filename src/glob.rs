@@ -0,0 +1,130 @@
+//! Pathname expansion: finds the files a pattern like `src/*.rs` matches.
+
+use std::fs;
+
+use crate::pattern;
+
+/// Returns the paths that `pattern` matches, sorted. Names starting with a `.` only match a
+/// pattern that starts with a literal `.` too. With `globstar` a `**` component matches any
+/// number of directories, or all files and directories if it comes last.
+pub fn glob(pattern: &str, globstar: bool) -> Vec<String> {
+    let components = components(pattern);
+    // the paths matched so far, each ending in a `/` unless it is the empty start
+    let mut paths = vec![if pattern.starts_with('/') {
+        "/".to_string()
+    } else {
+        String::new()
+    }];
+    let last = components.len() - 1;
+    for (i, component) in components.iter().enumerate() {
+        if component.is_empty() {
+            continue;
+        }
+        let is_last = i == last;
+        let mut next = Vec::new();
+        for path in &paths {
+            if globstar && component == "**" {
+                // zero directories, which leaves the directory itself as a match at the end
+                next.push(path.clone());
+                descendants(path, !is_last, &mut next);
+            } else if !pattern::has_meta(component) {
+                let path = format!("{path}{}", unescape(component));
+                if fs::symlink_metadata(&path).is_ok() {
+                    next.push(path);
+                }
+            } else {
+                matching(path, component, &mut next);
+            }
+        }
+        // all but the last component name directories
+        paths = next
+            .into_iter()
+            .filter_map(|path| {
+                if is_last || path.is_empty() || path.ends_with('/') {
+                    Some(path)
+                } else if fs::metadata(&path).is_ok_and(|m| m.is_dir()) {
+                    Some(path + "/")
+                } else {
+                    None
+                }
+            })
+            .collect();
+    }
+    // the start itself is no match, as with a pattern that is only `**/`
+    paths.retain(|path| !path.is_empty());
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+/// Splits a pattern at its slashes. A pattern ending in a `/` ends with an empty component.
+fn components(pattern: &str) -> Vec<String> {
+    let mut components = vec![String::new()];
+    let mut chars = pattern.trim_start_matches('/').chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '/' => components.push(String::new()),
+            '\\' => {
+                let current = components.last_mut().unwrap();
+                match chars.next() {
+                    Some('/') => components.push(String::new()),
+                    Some(c) => {
+                        current.push('\\');
+                        current.push(c);
+                    }
+                    None => current.push('\\'),
+                }
+            }
+            c => components.last_mut().unwrap().push(c),
+        }
+    }
+    components
+}
+
+/// Adds the entries of the directory `dir` that `pattern` matches to `paths`.
+fn matching(dir: &str, pattern: &str, paths: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+        return;
+    };
+    let hidden = pattern.starts_with('.') || pattern.starts_with("\\.");
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if (hidden || !name.starts_with('.')) && pattern::matches(pattern, &name) {
+            paths.push(format!("{dir}{name}"));
+        }
+    }
+}
+
+/// Adds everything below `dir` to `paths`, or only the directories if `dirs_only`. Hidden
+/// entries are skipped and symbolic links are not followed.
+fn descendants(dir: &str, dirs_only: bool, paths: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(if dir.is_empty() { "." } else { dir }) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with('.') {
+            continue;
+        }
+        let path = format!("{dir}{name}");
+        if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            paths.push(path.clone());
+            descendants(&(path + "/"), dirs_only, paths);
+        } else if !dirs_only {
+            paths.push(path);
+        }
+    }
+}
+
+/// Removes the backslashes that make characters of a pattern literal.
+fn unescape(pattern: &str) -> String {
+    let mut res = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => res.push(chars.next().unwrap_or('\\')),
+            c => res.push(c),
+        }
+    }
+    res
+}
@@ -0,0 +1,242 @@
+//! History expansion, which an interactive shell applies to each line before parsing it.
+//! `!!` is the previous command, `!n` the command numbered `n`, `!-n` the one `n` commands
+//! back, `!prefix` the last one starting with `prefix` and `!?text?` the last one containing
+//! `text`. A word designator like `:1`, `:$` or `:*` picks words of the command, and `!$`
+//! is short for `!!:$`. `^old^new` repeats the previous command with `old` replaced.
+//! Nothing is expanded inside single quotes or after a backslash.
+
+use crate::{Result, ShellError};
+
+pub fn expand(entries: &[String], line: &str) -> Result<String> {
+    if let Some(rest) = line.strip_prefix('^') {
+        return quick_substitution(entries, rest);
+    }
+    let mut expanded = String::new();
+    let mut single = false;
+    let mut double = false;
+    let mut i = 0;
+    while let Some(c) = line[i..].chars().next() {
+        i += c.len_utf8();
+        match c {
+            '\'' if !double => single = !single,
+            '"' if !single => double = !double,
+            '\\' if !single => {
+                expanded.push(c);
+                let Some(c) = line[i..].chars().next() else {
+                    break;
+                };
+                i += c.len_utf8();
+                expanded.push(c);
+                continue;
+            }
+            '!' if !single => {
+                if let Some((text, len)) = event(entries, &line[i..], double)? {
+                    expanded += &text;
+                    i += len;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        expanded.push(c);
+    }
+    Ok(expanded)
+}
+
+/// Expands what follows a `!`, returning the text and the length of the expression, or
+/// `None` if the `!` starts none.
+fn event(entries: &[String], rest: &str, quoted: bool) -> Result<Option<(String, usize)>> {
+    let Some(first) = rest.chars().next() else {
+        return Ok(None);
+    };
+    if first.is_whitespace() || first == '=' || first == '(' || (quoted && first == '"') {
+        return Ok(None);
+    }
+    let not_found = |len: usize| ShellError::EventNotFound(format!("!{}", &rest[..len]));
+    let back = |n: usize| entries.len().checked_sub(n);
+    let (entry, mut len) = match first {
+        '!' => (back(1), 1),
+        // a word designator right after the `!` applies to the previous command
+        '$' | '^' | '*' | ':' => (back(1), 0),
+        '?' => {
+            let text = rest[1..].split('?').next().unwrap_or_default();
+            let len = (1 + text.len() + 1).min(rest.len());
+            let found = entries.iter().rposition(|entry| entry.contains(text));
+            (Some(found.ok_or_else(|| not_found(len))?), len)
+        }
+        _ => {
+            let len = rest
+                .find(|c: char| c.is_whitespace() || ":;&|<>()\"'`$^*".contains(c))
+                .unwrap_or(rest.len());
+            let spec = &rest[..len];
+            if spec.is_empty() {
+                return Ok(None);
+            }
+            let number = spec.strip_prefix('-').unwrap_or(spec);
+            if !number.is_empty() && number.bytes().all(|b| b.is_ascii_digit()) {
+                let n: usize = number.parse().map_err(|_| not_found(len))?;
+                match spec.starts_with('-') {
+                    true => (back(n), len),
+                    false => (n.checked_sub(1), len),
+                }
+            } else {
+                let found = entries.iter().rposition(|entry| entry.starts_with(spec));
+                (Some(found.ok_or_else(|| not_found(len))?), len)
+            }
+        }
+    };
+    let entry = entry
+        .and_then(|i| entries.get(i))
+        .ok_or_else(|| not_found(len.max(1)))?;
+    let designator = match rest[len..].chars().next() {
+        Some(':') => {
+            let end = rest[len + 1..]
+                .find(|c: char| !(c.is_ascii_digit() || "^$*-".contains(c)))
+                .map_or(rest.len(), |end| len + 1 + end);
+            let designator = &rest[len + 1..end];
+            len = end;
+            designator
+        }
+        Some(c @ ('$' | '^' | '*')) => {
+            len += 1;
+            &rest[len - 1..len - 1 + c.len_utf8()]
+        }
+        _ => return Ok(Some((entry.clone(), len))),
+    };
+    let words = words(entry);
+    let bad = || ShellError::BadWordSpecifier(format!("!{}", &rest[..len]));
+    let index = |spec: &str| match spec {
+        "^" => Ok(1),
+        "$" => Ok(words.len().saturating_sub(1)),
+        _ => spec.parse::<usize>().map_err(|_| bad()),
+    };
+    let (start, end) = match designator {
+        "*" => (1, words.len().saturating_sub(1)),
+        _ if designator.ends_with('*') => {
+            let start = index(&designator[..designator.len() - 1])?;
+            (start, words.len().saturating_sub(1))
+        }
+        _ => match designator.split_once('-') {
+            Some((start, "")) => (index(start)?, words.len().saturating_sub(2)),
+            Some(("", end)) => (0, index(end)?),
+            Some((start, end)) => (index(start)?, index(end)?),
+            None => (index(designator)?, index(designator)?),
+        },
+    };
+    if designator == "*" && words.len() < 2 {
+        return Ok(Some((String::new(), len)));
+    }
+    if start > end || end >= words.len() {
+        return Err(bad());
+    }
+    Ok(Some((words[start..=end].join(" "), len)))
+}
+
+/// `^old^new^`, the previous command with the first `old` replaced by `new`. Whatever follows
+/// the last `^` is appended.
+fn quick_substitution(entries: &[String], rest: &str) -> Result<String> {
+    let mut parts = rest.splitn(3, '^');
+    let old = parts.next().unwrap_or_default();
+    let new = parts.next().unwrap_or_default();
+    let tail = parts.next().unwrap_or_default();
+    let failed = || ShellError::SubstitutionFailed(format!("^{rest}"));
+    let previous = entries.last().ok_or_else(failed)?;
+    if old.is_empty() || !previous.contains(old) {
+        return Err(failed());
+    }
+    Ok(previous.replacen(old, new, 1) + tail)
+}
+
+/// Splits a command into the words that designators count, keeping quotes.
+fn words(command: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quote = None;
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (c, quote) {
+            ('\\', _) if quote != Some('\'') => {
+                word.push(c);
+                word.extend(chars.next());
+                continue;
+            }
+            ('\'' | '"', None) => quote = Some(c),
+            (c, Some(q)) if c == q => quote = None,
+            (c, None) if c.is_whitespace() => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                continue;
+            }
+            _ => {}
+        }
+        word.push(c);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries() -> Vec<String> {
+        ["echo one two three", "ls -l /tmp", "cat 'a b' c"]
+            .map(String::from)
+            .to_vec()
+    }
+
+    fn expanded(line: &str) -> String {
+        expand(&entries(), line).unwrap()
+    }
+
+    #[test]
+    fn events() {
+        assert_eq!(expanded("!!"), "cat 'a b' c");
+        assert_eq!(expanded("!1 four"), "echo one two three four");
+        assert_eq!(expanded("!-2"), "ls -l /tmp");
+        assert_eq!(expanded("!ec"), "echo one two three");
+        assert_eq!(expanded("!?tmp?"), "ls -l /tmp");
+    }
+
+    #[test]
+    fn word_designators() {
+        assert_eq!(expanded("echo !$"), "echo c");
+        assert_eq!(expanded("echo !^"), "echo 'a b'");
+        assert_eq!(expanded("echo !1:2"), "echo two");
+        assert_eq!(expanded("echo !1:1-2"), "echo one two");
+        assert_eq!(expanded("echo !1:*"), "echo one two three");
+        assert_eq!(expanded("echo !1:2*"), "echo two three");
+        assert_eq!(expanded("!!:0"), "cat");
+    }
+
+    #[test]
+    fn quick_substitution() {
+        assert_eq!(expanded("^c^x"), "xat 'a b' c");
+        assert_eq!(expanded("^c^x^ d"), "xat 'a b' c d");
+        let res = expand(&entries(), "^zz^x");
+        assert!(matches!(res, Err(ShellError::SubstitutionFailed(_))));
+    }
+
+    #[test]
+    fn not_expanded() {
+        for line in ["echo '!!'", "echo \\!!", "echo ! a", "a != b", "echo \"!\""] {
+            assert_eq!(expanded(line), line);
+        }
+        assert_eq!(expanded("echo \"!!\""), "echo \"cat 'a b' c\"");
+    }
+
+    #[test]
+    fn errors() {
+        let res = expand(&entries(), "!9");
+        assert!(matches!(res, Err(ShellError::EventNotFound(e)) if e == "!9"));
+        let res = expand(&entries(), "!nope");
+        assert!(matches!(res, Err(ShellError::EventNotFound(e)) if e == "!nope"));
+        let res = expand(&entries(), "!1:7");
+        assert!(matches!(res, Err(ShellError::BadWordSpecifier(_))));
+        let res = expand(&[], "!!");
+        assert!(matches!(res, Err(ShellError::EventNotFound(_))));
+    }
+}
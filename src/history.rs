@@ -0,0 +1,237 @@
+//! The commands of the interactive shell, which Up and Down go through. They are kept in
+//! `$HISTFILE`, `~/.shell_history` by default. Every command is appended to the file as soon
+//! as it is entered, under a lock, so that sessions sharing the file do not lose each other's
+//! commands. Lines of a command that spans several are joined by a trailing backslash, and
+//! the backslashes a line ends with are doubled, so that only an odd number joins lines.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, Write},
+    os::fd::AsRawFd,
+    path::PathBuf,
+};
+
+use crate::{expand_tilde, Shell};
+
+/// The number of commands kept when `HISTSIZE` or `HISTFILESIZE` is not set.
+const DEFAULT_SIZE: usize = 500;
+
+#[derive(Debug, Default)]
+pub struct History {
+    entries: Vec<String>,
+    /// The entry being shown while going through the history, `entries.len()` for the line
+    /// that was being typed.
+    position: usize,
+    /// The line that was being typed before going up, which going back down restores.
+    draft: String,
+}
+
+impl History {
+    pub fn entries(&self) -> &[String] {
+        &self.entries
+    }
+
+    /// Goes back to the line being typed, which the next Up starts from.
+    pub fn reset(&mut self) {
+        self.position = self.entries.len();
+        self.draft.clear();
+    }
+
+    /// The entry before the one shown, for Up. `line` is what is being edited, which is kept
+    /// if it is the line that was being typed.
+    pub fn up(&mut self, line: &str) -> Option<&str> {
+        if self.position == 0 {
+            return None;
+        }
+        if self.position == self.entries.len() {
+            self.draft = line.into();
+        }
+        self.position -= 1;
+        Some(&self.entries[self.position])
+    }
+
+    /// The entry after the one shown, or the line that was being typed, for Down.
+    pub fn down(&mut self) -> Option<&str> {
+        if self.position >= self.entries.len() {
+            return None;
+        }
+        self.position += 1;
+        match self.entries.get(self.position) {
+            Some(entry) => Some(entry),
+            None => Some(&self.draft),
+        }
+    }
+
+    /// Keeps only the last `size` entries.
+    fn truncate(&mut self, size: Option<usize>) {
+        if let Some(size) = size {
+            let excess = self.entries.len().saturating_sub(size);
+            self.entries.drain(..excess);
+        }
+    }
+}
+
+/// How many entries to keep according to a variable like `HISTSIZE`. A value that is no
+/// number, or a negative one, means no limit.
+fn limit(value: Option<String>) -> Option<usize> {
+    match value {
+        None => Some(DEFAULT_SIZE),
+        Some(value) => value.trim().parse().ok(),
+    }
+}
+
+/// Splits the contents of a history file into entries.
+fn entries(contents: &str) -> Vec<String> {
+    let mut entries = Vec::new();
+    let mut continued = false;
+    for line in contents.lines() {
+        let backslashes = line.len() - line.trim_end_matches('\\').len();
+        let continues = backslashes % 2 == 1;
+        let line = &line[..line.len() - backslashes.div_ceil(2)];
+        match entries.last_mut() {
+            Some(entry) if continued => {
+                *entry += "\n";
+                *entry += line;
+            }
+            _ => entries.push(line.to_string()),
+        }
+        continued = continues;
+    }
+    entries
+}
+
+/// Writes an entry as one line of a history file, or several joined by backslashes.
+fn line(entry: &str) -> String {
+    let lines: Vec<String> = entry
+        .split('\n')
+        .map(|line| {
+            let backslashes = line.len() - line.trim_end_matches('\\').len();
+            format!("{line}{}", "\\".repeat(backslashes))
+        })
+        .collect();
+    format!("{}\n", lines.join("\\\n"))
+}
+
+/// Locks a history file until it is closed, which is where other sessions wait to write.
+fn lock(file: &File) -> io::Result<()> {
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+impl Shell {
+    /// The file that keeps the history, if any.
+    fn history_file(&self) -> Option<PathBuf> {
+        match self.var("HISTFILE") {
+            Some(path) if path.is_empty() => None,
+            Some(path) => Some(path.into()),
+            None => Some(expand_tilde("~/.shell_history")),
+        }
+    }
+
+    /// Reads the history of earlier sessions.
+    pub fn load_history(&mut self) {
+        let Some(path) = self.history_file() else {
+            return;
+        };
+        if let Ok(contents) = std::fs::read_to_string(path) {
+            self.history.entries = entries(&contents);
+        }
+        self.history.truncate(limit(self.var("HISTSIZE")));
+        self.history.reset();
+    }
+
+    /// Adds a command to the history and appends it to the history file, unless `HISTCONTROL`
+    /// says otherwise: `ignorespace` skips commands starting with a space, `ignoredups` ones
+    /// that repeat the previous command, `ignoreboth` is both, and `erasedups` removes
+    /// earlier copies of the command.
+    pub fn add_history(&mut self, command: &str) {
+        let command = command.trim_end_matches('\n');
+        self.history.reset();
+        if command.trim().is_empty() {
+            return;
+        }
+        let control = self.var("HISTCONTROL").unwrap_or_default();
+        let control: Vec<&str> = control.split(':').collect();
+        let both = control.contains(&"ignoreboth");
+        if (both || control.contains(&"ignorespace")) && command.starts_with(' ') {
+            return;
+        }
+        let entries = &mut self.history.entries;
+        let ignore_dups = both || control.contains(&"ignoredups");
+        if ignore_dups && entries.last().is_some_and(|entry| entry == command) {
+            return;
+        }
+        if control.contains(&"erasedups") {
+            entries.retain(|entry| entry != command);
+        }
+        entries.push(command.into());
+        self.history.truncate(limit(self.var("HISTSIZE")));
+        self.history.reset();
+        if let Some(path) = self.history_file() {
+            // the history is no reason to fail a command
+            _ = append(path, command, limit(self.var("HISTFILESIZE")));
+        }
+    }
+}
+
+/// Appends an entry to a history file and cuts the file down to its last `size` entries.
+/// The file is locked throughout and trimmed in place, so that the appends of other sessions
+/// go to the same file and none are lost.
+fn append(path: PathBuf, entry: &str, size: Option<usize>) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+    lock(&file)?;
+    file.write_all(line(entry).as_bytes())?;
+    let Some(size) = size else {
+        return Ok(());
+    };
+    let mut contents = String::new();
+    file.rewind()?;
+    file.read_to_string(&mut contents)?;
+    let entries = entries(&contents);
+    if entries.len() > size {
+        let kept: String = entries[entries.len() - size..]
+            .iter()
+            .map(|entry| line(entry))
+            .collect();
+        file.set_len(0)?;
+        file.write_all(kept.as_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lines_continue_after_a_single_backslash() {
+        assert_eq!(entries("a\nb\\\nc\nd\n"), ["a", "b\nc", "d"]);
+        assert_eq!(line("b\nc"), "b\\\nc\n");
+    }
+
+    #[test]
+    fn trailing_backslashes_are_doubled() {
+        assert_eq!(line("a\\"), "a\\\\\n");
+        assert_eq!(entries("a\\\\\nb\n"), ["a\\", "b"]);
+        assert_eq!(entries("a\\\\\\\nb\n"), ["a\\\nb"]);
+    }
+
+    #[test]
+    fn entries_survive_a_round_trip() {
+        let written = [
+            "echo a\\",
+            "a\\\\",
+            "for x\ndo \\\n  echo $x\\\ndone",
+            "\\",
+            "",
+        ];
+        let contents: String = written.iter().map(|entry| line(entry)).collect();
+        assert_eq!(entries(&contents), written);
+    }
+}
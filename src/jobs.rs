@@ -0,0 +1,460 @@
+//! Job control: commands ending with `&` run as jobs in the background, and with an
+//! interactive shell every job gets a process group of its own, which owns the terminal while
+//! it runs in the foreground. `jobs`, `fg`, `bg` and `wait` work on the table of jobs.
+
+use std::io;
+
+use libc::pid_t;
+
+use crate::{signals, OutPut, Output, Result, Shell, ShellError, Stage};
+
+/// Sets up job control for an interactive shell: waits until the shell is in the foreground,
+/// ignores the signals meant for its jobs and gives it a process group of its own that owns
+/// the terminal. Returns that group.
+pub fn init() -> pid_t {
+    unsafe {
+        while libc::tcgetpgrp(0) != libc::getpgrp() {
+            libc::kill(0, libc::SIGTTIN);
+        }
+        signals::ignore();
+        // fails for a session leader, which already leads its group
+        libc::setpgid(0, 0);
+        let pgid = libc::getpgrp();
+        libc::tcsetpgrp(0, pgid);
+        pgid
+    }
+}
+
+/// The process group the processes of a job join as they are started.
+#[derive(Debug, Clone, Copy)]
+pub struct Group {
+    /// 0 until the first process starts, which leads the group.
+    pub pgid: pid_t,
+    /// Whether the group gets the terminal.
+    pub foreground: bool,
+}
+
+impl Group {
+    /// Moves the calling process, a child that was just started, into the group. The shell
+    /// does the same from its side, as either may run first. Safe between fork and exec.
+    pub fn join(self) {
+        unsafe {
+            libc::setpgid(0, self.pgid);
+            if self.foreground {
+                libc::tcsetpgrp(0, libc::getpgrp());
+            }
+        }
+    }
+}
+
+/// A change in the state of a child process.
+enum Event {
+    Exited(i32),
+    Stopped,
+    Continued,
+}
+
+/// Waits for the state of a child to change, or only checks for a change if not `block`.
+/// A caught `SIGINT` ends the wait with an `Interrupted` error.
+fn wait_pid(pid: pid_t, block: bool) -> Result<Option<Event>> {
+    let mut status = 0;
+    let flags = libc::WUNTRACED | libc::WCONTINUED | if block { 0 } else { libc::WNOHANG };
+    loop {
+        match unsafe { libc::waitpid(pid, &mut status, flags) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted || signals::interrupted() {
+                    return Err(err.into());
+                }
+            }
+            0 => return Ok(None),
+            _ => break,
+        }
+    }
+    Ok(Some(if libc::WIFSTOPPED(status) {
+        Event::Stopped
+    } else if libc::WIFCONTINUED(status) {
+        Event::Continued
+    } else if libc::WIFSIGNALED(status) {
+        Event::Exited(128 + libc::WTERMSIG(status))
+    } else {
+        Event::Exited(libc::WEXITSTATUS(status))
+    }))
+}
+
+#[derive(Debug)]
+pub struct Job {
+    /// The number of the job, `%n`, which it gets once it is in the table.
+    id: usize,
+    /// The process group of the job, 0 without job control.
+    pgid: pid_t,
+    /// The processes of the job in pipeline order, with their exit statuses once they are
+    /// done. Stages that could not be started have no pid.
+    processes: Vec<(Option<pid_t>, Option<i32>)>,
+    command: String,
+    stopped: bool,
+}
+
+impl Job {
+    /// A job of started stages. The first one that runs leads the process group, if there
+    /// is one.
+    pub fn new(stages: Vec<Stage>, command: String, job_control: bool) -> Self {
+        let processes: Vec<_> = stages
+            .into_iter()
+            .map(|stage| match stage {
+                Stage::Started(pid) => (Some(pid), None),
+                Stage::Exited(status) => (None, Some(status)),
+            })
+            .collect();
+        let pgid = match processes.iter().find_map(|(pid, _)| *pid) {
+            Some(pid) if job_control => pid,
+            _ => 0,
+        };
+        Self {
+            id: 0,
+            pgid,
+            processes,
+            command,
+            stopped: false,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.processes.iter().all(|(_, status)| status.is_some())
+    }
+
+    /// The exit status of the last process.
+    fn status(&self) -> i32 {
+        self.processes
+            .last()
+            .and_then(|(_, status)| *status)
+            .unwrap_or_default()
+    }
+
+    /// Collects the state changes of the processes and returns whether there were any. With
+    /// `block` it waits until the job is done or stopped.
+    fn update(&mut self, block: bool) -> Result<bool> {
+        let mut changed = false;
+        for (pid, status) in &mut self.processes {
+            let Some(pid) = *pid else {
+                continue;
+            };
+            while status.is_none() {
+                let Some(event) = wait_pid(pid, block)? else {
+                    break;
+                };
+                changed = true;
+                match event {
+                    Event::Exited(code) => *status = Some(code),
+                    Event::Stopped => {
+                        self.stopped = true;
+                        if block {
+                            return Ok(true);
+                        }
+                        break;
+                    }
+                    Event::Continued => self.stopped = false,
+                }
+            }
+        }
+        if self.is_done() {
+            self.stopped = false;
+        }
+        Ok(changed)
+    }
+
+    /// The state as `jobs` shows it.
+    fn state(&self) -> String {
+        if self.stopped {
+            "Stopped".into()
+        } else if !self.is_done() {
+            "Running".into()
+        } else {
+            match self.status() {
+                0 => "Done".into(),
+                code => format!("Exit {code}"),
+            }
+        }
+    }
+
+    fn pid(&self) -> Option<pid_t> {
+        self.processes.iter().rev().find_map(|(pid, _)| *pid)
+    }
+}
+
+/// The jobs of the shell, most recently started, stopped or resumed last. The last one is the
+/// current job, `%+`, and the one before it the previous job, `%-`.
+#[derive(Debug, Default)]
+pub struct Jobs {
+    jobs: Vec<Job>,
+}
+
+impl Jobs {
+    /// Adds a job as the current one. A new job is numbered one more than the highest number
+    /// in use.
+    fn add(&mut self, mut job: Job) -> usize {
+        if job.id == 0 {
+            job.id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        }
+        let id = job.id;
+        self.jobs.push(job);
+        id
+    }
+
+    /// `+` for the current job, `-` for the previous one.
+    fn mark(&self, index: usize) -> char {
+        match self.jobs.len() - index {
+            1 => '+',
+            2 => '-',
+            _ => ' ',
+        }
+    }
+
+    /// Finds the job a spec like `%2`, `%+`, `%-` or `%prefix` names. Without a spec it is
+    /// the current job.
+    fn find(&self, builtin: &str, spec: Option<&str>) -> Result<usize> {
+        let no_such_job =
+            |spec: &str| ShellError::Param(builtin.into(), format!("{spec}: no such job"));
+        let Some(spec) = spec else {
+            return self
+                .jobs
+                .len()
+                .checked_sub(1)
+                .ok_or_else(|| no_such_job("current"));
+        };
+        let name = spec.strip_prefix('%').unwrap_or(spec);
+        let found = match name {
+            "" | "%" | "+" => self.jobs.len().checked_sub(1),
+            "-" => self.jobs.len().checked_sub(2),
+            _ => match name.parse::<usize>() {
+                Ok(id) => self.jobs.iter().position(|job| job.id == id),
+                Err(_) => {
+                    let mut matching = self
+                        .jobs
+                        .iter()
+                        .enumerate()
+                        .filter(|(_, job)| job.command.starts_with(name));
+                    match (matching.next(), matching.next()) {
+                        (Some((i, _)), None) => Some(i),
+                        (Some(_), Some(_)) => {
+                            return Err(ShellError::Param(
+                                builtin.into(),
+                                format!("{spec}: ambiguous job spec"),
+                            ))
+                        }
+                        _ => None,
+                    }
+                }
+            },
+        };
+        found.ok_or_else(|| no_such_job(spec))
+    }
+
+    /// Lists the jobs by number as `jobs` does, with their pids for `-l`, or only the pids
+    /// of their process group leaders for `-p`.
+    pub fn listing(&self, pids: bool, only_pids: bool) -> String {
+        let mut order: Vec<usize> = (0..self.jobs.len()).collect();
+        order.sort_by_key(|&i| self.jobs[i].id);
+        let mut listing = String::new();
+        for i in order {
+            let job = &self.jobs[i];
+            let pid = match job.pgid {
+                0 => job.pid().unwrap_or_default(),
+                pgid => pgid,
+            };
+            if only_pids {
+                listing += &format!("{pid}\n");
+                continue;
+            }
+            listing += &format!("[{}]{} ", job.id, self.mark(i));
+            if pids {
+                listing += &format!("{pid} ");
+            }
+            let background = if job.stopped || job.is_done() {
+                ""
+            } else {
+                " &"
+            };
+            listing += &format!(" {:<24}{}{background}\n", job.state(), job.command);
+        }
+        listing
+    }
+}
+
+impl Shell {
+    /// Makes a forked copy of the shell a subshell, which has no job control and no jobs of
+    /// its own, and which gets the default actions of the signals the shell ignores.
+    pub fn enter_subshell(&mut self) {
+        signals::reset();
+        self.job_control = false;
+        self.group = None;
+        self.jobs = Jobs::default();
+    }
+
+    /// The process group a process being started joins, which without a job being started
+    /// is a foreground group of its own. `None` without job control.
+    pub fn group(&self) -> Option<Group> {
+        self.group.or(self.job_control.then_some(Group {
+            pgid: 0,
+            foreground: true,
+        }))
+    }
+
+    /// Puts a process that was just started into its group, see [`Group::join`].
+    pub fn started(&mut self, pid: pid_t) {
+        let Some(group) = self.group() else {
+            return;
+        };
+        let pgid = if group.pgid == 0 { pid } else { group.pgid };
+        unsafe { libc::setpgid(pid, pgid) };
+        if let Some(group) = &mut self.group {
+            group.pgid = pgid;
+        }
+    }
+
+    /// Gives the terminal to the job and waits for it to finish or stop, then takes the
+    /// terminal back. A stopped job goes to the table and returns 128 plus `SIGTSTP`.
+    pub fn foreground(&mut self, mut job: Job) -> Result<i32> {
+        if self.job_control && job.pgid != 0 {
+            unsafe { libc::tcsetpgrp(0, job.pgid) };
+        }
+        let res = job.update(true);
+        if self.job_control {
+            unsafe { libc::tcsetpgrp(0, self.pgid) };
+        }
+        res?;
+        if job.stopped {
+            println!();
+            let id = self.jobs.add(job);
+            let job = self.jobs.jobs.last().expect("job was added");
+            println!("[{id}]+  {:<24}{}", job.state(), job.command);
+            return Ok(128 + libc::SIGTSTP);
+        }
+        Ok(job.status())
+    }
+
+    /// Adds a job that was started in the background to the table and sets `$!` to its
+    /// last process. An interactive shell prints its number and pid.
+    pub fn background(&mut self, job: Job) {
+        self.last_background = job.pid();
+        let id = self.jobs.add(job);
+        if self.job_control {
+            println!("[{id}] {}", self.last_background.unwrap_or_default());
+        }
+    }
+
+    /// Collects the state changes of the jobs. An interactive shell reports the jobs that
+    /// finished or stopped, and forgets the finished ones. A script keeps them for `wait`.
+    pub fn notify(&mut self) -> Result<()> {
+        for i in 0..self.jobs.jobs.len() {
+            let changed = self.jobs.jobs[i].update(false)?;
+            let job = &self.jobs.jobs[i];
+            if self.job_control && changed && (job.stopped || job.is_done()) {
+                println!(
+                    "[{}]{}  {:<24}{}",
+                    job.id,
+                    self.jobs.mark(i),
+                    job.state(),
+                    job.command
+                );
+            }
+        }
+        if self.job_control {
+            self.jobs.jobs.retain(|job| !job.is_done());
+        }
+        Ok(())
+    }
+
+    /// `fg`: resumes a job in the foreground, printing its command to `out`.
+    pub fn fg(&mut self, spec: Option<&str>, out: &Output) -> Result<i32> {
+        if !self.job_control {
+            return Err(ShellError::Param("fg".into(), "no job control".into()));
+        }
+        let i = self.jobs.find("fg", spec)?;
+        out.println(&self.jobs.jobs[i].command)?;
+        let mut job = self.jobs.jobs.remove(i);
+        if job.stopped {
+            unsafe { libc::killpg(job.pgid, libc::SIGCONT) };
+            job.stopped = false;
+        }
+        self.foreground(job)
+    }
+
+    /// `bg`: resumes a stopped job in the background, printing it to `out`.
+    pub fn bg(&mut self, spec: Option<&str>, out: &Output) -> Result<()> {
+        if !self.job_control {
+            return Err(ShellError::Param("bg".into(), "no job control".into()));
+        }
+        let i = self.jobs.find("bg", spec)?;
+        let mut job = self.jobs.jobs.remove(i);
+        if job.stopped {
+            unsafe { libc::killpg(job.pgid, libc::SIGCONT) };
+            job.stopped = false;
+        }
+        let line = format!("[{}]+ {} &", job.id, job.command);
+        self.jobs.add(job);
+        out.println(&line)
+    }
+
+    /// `wait`: waits for the jobs named by job specs or pids and returns the status of the
+    /// last one, or for all jobs without arguments, which returns 0. In an interactive shell
+    /// Ctrl-C stops waiting, which returns 130.
+    pub fn wait(&mut self, specs: &[String]) -> Result<i32> {
+        if !self.job_control {
+            return self.wait_for(specs);
+        }
+        signals::catch_interrupt();
+        let status = self.wait_for(specs);
+        signals::ignore_interrupt();
+        match status {
+            Err(ShellError::IO(e)) if e.kind() == io::ErrorKind::Interrupted => {
+                Ok(128 + libc::SIGINT)
+            }
+            status => status,
+        }
+    }
+
+    fn wait_for(&mut self, specs: &[String]) -> Result<i32> {
+        if specs.is_empty() {
+            for job in &mut self.jobs.jobs {
+                while !job.is_done() && !job.stopped {
+                    job.update(true)?;
+                }
+            }
+            self.jobs.jobs.retain(|job| !job.is_done());
+            return Ok(0);
+        }
+        let mut status = 0;
+        for spec in specs {
+            let i = if spec.starts_with('%') {
+                self.jobs.find("wait", Some(spec))?
+            } else {
+                let pid = spec.parse::<pid_t>().map_err(|_| {
+                    ShellError::Param(
+                        "wait".into(),
+                        format!("`{spec}': not a pid or valid job spec"),
+                    )
+                })?;
+                self.jobs
+                    .jobs
+                    .iter()
+                    .position(|job| job.processes.iter().any(|(p, _)| *p == Some(pid)))
+                    .ok_or_else(|| {
+                        ShellError::Param(
+                            "wait".into(),
+                            format!("pid {pid} is not a child of this shell"),
+                        )
+                    })?
+            };
+            let job = &mut self.jobs.jobs[i];
+            while !job.is_done() && !job.stopped {
+                job.update(true)?;
+            }
+            status = job.status();
+            if job.is_done() {
+                self.jobs.jobs.remove(i);
+            }
+        }
+        Ok(status)
+    }
+}
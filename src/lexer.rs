@@ -0,0 +1,928 @@
+use std::{fmt::Display, iter::Peekable, str::Chars};
+
+use crate::{
+    vars::{is_name, quote},
+    Result, ShellError,
+};
+
+/// A piece of a word, remembering whether it was quoted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WordPart {
+    /// Literal text. Quoted text has had its quotes and escapes removed.
+    Text { text: String, quoted: bool },
+    /// A parameter expansion such as `$HOME` or `${PATH:-/bin}`.
+    Param { param: Param, quoted: bool },
+    /// The source of a command substitution, `$(source)` or `` `source` ``.
+    Command { source: String, quoted: bool },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Param {
+    /// A variable name, a positional parameter or a special parameter like `?`.
+    pub name: String,
+    pub op: Option<ParamOp>,
+}
+
+/// What to do with the value of a parameter. `colon` means the operator applies to empty
+/// values as well as unset ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParamOp {
+    /// `${#name}`
+    Length,
+    /// `${name:-word}`
+    Default { colon: bool, word: Word },
+    /// `${name:=word}`
+    Assign { colon: bool, word: Word },
+    /// `${name:?word}`
+    Error { colon: bool, word: Word },
+    /// `${name:+word}`
+    Alternate { colon: bool, word: Word },
+    /// `${name#pattern}` and `${name##pattern}`
+    RemovePrefix { longest: bool, pattern: Word },
+    /// `${name%pattern}` and `${name%%pattern}`
+    RemoveSuffix { longest: bool, pattern: Word },
+    /// `${name/pattern/replacement}` and its `//`, `/#` and `/%` forms
+    Replace {
+        mode: ReplaceMode,
+        pattern: Word,
+        replacement: Word,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplaceMode {
+    First,
+    All,
+    Prefix,
+    Suffix,
+}
+
+/// A shell word made of the parts it was written with, e.g. `a"b $?"` is an unquoted `a`,
+/// a quoted `b ` and a quoted `$?`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Word {
+    pub parts: Vec<WordPart>,
+}
+
+impl Word {
+    fn push_char(&mut self, c: char, quoted: bool) {
+        if let Some(WordPart::Text { text, quoted: q }) = self.parts.last_mut() {
+            if *q == quoted {
+                text.push(c);
+                return;
+            }
+        }
+        self.parts.push(WordPart::Text {
+            text: c.into(),
+            quoted,
+        });
+    }
+
+    /// The text of a word that consists of unquoted text only.
+    pub fn as_unquoted(&self) -> Option<&str> {
+        match self.parts.as_slice() {
+            [WordPart::Text {
+                text,
+                quoted: false,
+            }] => Some(text),
+            _ => None,
+        }
+    }
+
+    /// Splits a word like `NAME=value` into the name and the value. The name and the `=` have
+    /// to be unquoted.
+    pub fn as_assignment(&self) -> Option<(String, Word)> {
+        let Some(WordPart::Text {
+            text,
+            quoted: false,
+        }) = self.parts.first()
+        else {
+            return None;
+        };
+        let (name, value) = text.split_once('=')?;
+        if !is_name(name) {
+            return None;
+        }
+        let mut parts = Vec::new();
+        if !value.is_empty() {
+            parts.push(WordPart::Text {
+                text: value.into(),
+                quoted: false,
+            });
+        }
+        parts.extend(self.parts[1..].iter().cloned());
+        Some((name.into(), Word { parts }))
+    }
+}
+
+/// Writes the word back as shell source, quoting its quoted parts, so that reading the
+/// result again gives the same word.
+impl Display for Word {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for part in &self.parts {
+            match part {
+                WordPart::Text {
+                    text,
+                    quoted: false,
+                } => write!(f, "{text}")?,
+                WordPart::Text { text, quoted: true } => write!(f, "{}", quote(text))?,
+                WordPart::Param {
+                    param,
+                    quoted: false,
+                } => write!(f, "{param}")?,
+                WordPart::Param {
+                    param,
+                    quoted: true,
+                } => write!(f, "\"{param}\"")?,
+                WordPart::Command {
+                    source,
+                    quoted: false,
+                } => write!(f, "$({source})")?,
+                WordPart::Command {
+                    source,
+                    quoted: true,
+                } => write!(f, "\"$({source})\"")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Display for Param {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = &self.name;
+        let colon = |colon: &bool| if *colon { ":" } else { "" };
+        let twice = |twice: &bool, c: char| if *twice { format!("{c}{c}") } else { c.into() };
+        match &self.op {
+            None => write!(f, "${{{name}}}"),
+            Some(ParamOp::Length) => write!(f, "${{#{name}}}"),
+            Some(ParamOp::Default { colon: c, word }) => {
+                write!(f, "${{{name}{}-{word}}}", colon(c))
+            }
+            Some(ParamOp::Assign { colon: c, word }) => write!(f, "${{{name}{}={word}}}", colon(c)),
+            Some(ParamOp::Error { colon: c, word }) => write!(f, "${{{name}{}?{word}}}", colon(c)),
+            Some(ParamOp::Alternate { colon: c, word }) => {
+                write!(f, "${{{name}{}+{word}}}", colon(c))
+            }
+            Some(ParamOp::RemovePrefix { longest, pattern }) => {
+                write!(f, "${{{name}{}{pattern}}}", twice(longest, '#'))
+            }
+            Some(ParamOp::RemoveSuffix { longest, pattern }) => {
+                write!(f, "${{{name}{}{pattern}}}", twice(longest, '%'))
+            }
+            Some(ParamOp::Replace {
+                mode,
+                pattern,
+                replacement,
+            }) => {
+                let mode = match mode {
+                    ReplaceMode::First => "",
+                    ReplaceMode::All => "/",
+                    ReplaceMode::Prefix => "#",
+                    ReplaceMode::Suffix => "%",
+                };
+                write!(f, "${{{name}/{mode}{pattern}/{replacement}}}")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    Pipe,
+    And,
+    Or,
+    Semi,
+    /// `&`, which runs the commands before it in the background
+    Background,
+    /// `(` and `)`, which only come after the name of a function being defined
+    LParen,
+    RParen,
+}
+
+impl Display for Op {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Pipe => write!(f, "|"),
+            Self::And => write!(f, "&&"),
+            Self::Or => write!(f, "||"),
+            Self::Semi => write!(f, ";"),
+            Self::Background => write!(f, "&"),
+            Self::LParen => write!(f, "("),
+            Self::RParen => write!(f, ")"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedirOp {
+    /// `>`
+    Out,
+    /// `>>`
+    Append,
+    /// `>|`, which overwrites files even with `set -C`
+    Clobber,
+    /// `<`
+    In,
+    /// `<>`
+    ReadWrite,
+    /// `>&`, whose target is a descriptor to copy or `-` to close
+    DupOut,
+    /// `<&`
+    DupIn,
+    /// `&>`, for stdout and stderr
+    OutErr,
+    /// `&>>`
+    AppendErr,
+    /// `<<`, or `<<-` which strips leading tabs. The target of a here-document is its body.
+    HereDoc { strip_tabs: bool },
+    /// `<<<`
+    HereString,
+}
+
+impl Display for RedirOp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Out => write!(f, ">"),
+            Self::Append => write!(f, ">>"),
+            Self::Clobber => write!(f, ">|"),
+            Self::In => write!(f, "<"),
+            Self::ReadWrite => write!(f, "<>"),
+            Self::DupOut => write!(f, ">&"),
+            Self::DupIn => write!(f, "<&"),
+            Self::OutErr => write!(f, "&>"),
+            Self::AppendErr => write!(f, "&>>"),
+            Self::HereDoc { strip_tabs: false } => write!(f, "<<"),
+            Self::HereDoc { strip_tabs: true } => write!(f, "<<-"),
+            Self::HereString => write!(f, "<<<"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Word(Word),
+    Op(Op),
+    /// A redirection operator with the file descriptor written right in front of it.
+    Redirect(Option<u32>, RedirOp),
+    Newline,
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Word(w) => {
+                for part in &w.parts {
+                    match part {
+                        WordPart::Text { text, .. } => write!(f, "{text}")?,
+                        WordPart::Param { param, .. } => write!(f, "${{{}}}", param.name)?,
+                        WordPart::Command { source, .. } => write!(f, "$({source})")?,
+                    }
+                }
+                Ok(())
+            }
+            Self::Op(op) => write!(f, "{op}"),
+            Self::Redirect(Some(fd), op) => write!(f, "{fd}{op}"),
+            Self::Redirect(None, op) => write!(f, "{op}"),
+            Self::Newline => write!(f, "newline"),
+        }
+    }
+}
+
+/// Turns an input line into tokens.
+pub struct Lexer<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self {
+            chars: input.chars().peekable(),
+        }
+    }
+
+    /// Splits the input into tokens. The bodies of here-documents, which start on the line
+    /// after their operator, replace the delimiter words.
+    pub fn tokenize(mut self) -> Result<Vec<Token>> {
+        let mut tokens = Vec::new();
+        // the positions of the delimiters of here-documents whose bodies are still to come
+        let mut pending = Vec::new();
+        while let Some(token) = self.next_token()? {
+            match (&token, tokens.last()) {
+                (Token::Word(_), Some(Token::Redirect(_, RedirOp::HereDoc { .. }))) => {
+                    pending.push(tokens.len())
+                }
+                (Token::Newline, _) => {
+                    for i in pending.drain(..) {
+                        let Token::Redirect(_, RedirOp::HereDoc { strip_tabs }) = tokens[i - 1]
+                        else {
+                            unreachable!()
+                        };
+                        let Token::Word(delimiter) = &tokens[i] else {
+                            unreachable!()
+                        };
+                        tokens[i] = Token::Word(self.here_doc(delimiter, strip_tabs)?);
+                    }
+                }
+                _ => {}
+            }
+            tokens.push(token);
+        }
+        if let Some(&i) = pending.first() {
+            return Err(ShellError::HereDocEof(tokens[i].to_string()));
+        }
+        Ok(tokens)
+    }
+
+    fn next_token(&mut self) -> Result<Option<Token>> {
+        while self.chars.next_if(|c| *c == ' ' || *c == '\t').is_some() {}
+        let Some(&c) = self.chars.peek() else {
+            return Ok(None);
+        };
+        let token = match c {
+            // a comment runs to the end of the line
+            '#' => {
+                while self.chars.next_if(|c| *c != '\n').is_some() {}
+                return self.next_token();
+            }
+            '\n' => {
+                self.chars.next();
+                Token::Newline
+            }
+            '|' => {
+                self.chars.next();
+                if self.chars.next_if_eq(&'|').is_some() {
+                    Token::Op(Op::Or)
+                } else {
+                    Token::Op(Op::Pipe)
+                }
+            }
+            ';' => {
+                self.chars.next();
+                Token::Op(Op::Semi)
+            }
+            '(' => {
+                self.chars.next();
+                Token::Op(Op::LParen)
+            }
+            ')' => {
+                self.chars.next();
+                Token::Op(Op::RParen)
+            }
+            '&' if self.peek_second() == Some('&') => {
+                self.chars.nth(1);
+                Token::Op(Op::And)
+            }
+            '&' if self.peek_second() == Some('>') => {
+                self.chars.nth(1);
+                if self.chars.next_if_eq(&'>').is_some() {
+                    Token::Redirect(None, RedirOp::AppendErr)
+                } else {
+                    Token::Redirect(None, RedirOp::OutErr)
+                }
+            }
+            '&' => {
+                self.chars.next();
+                Token::Op(Op::Background)
+            }
+            '>' | '<' => self.redirect(None),
+            _ => return self.word().map(Some),
+        };
+        Ok(Some(token))
+    }
+
+    fn peek_second(&self) -> Option<char> {
+        self.chars.clone().nth(1)
+    }
+
+    fn redirect(&mut self, fd: Option<u32>) -> Token {
+        let op = match self.chars.next() {
+            Some('>') if self.chars.next_if_eq(&'>').is_some() => RedirOp::Append,
+            Some('>') if self.chars.next_if_eq(&'&').is_some() => RedirOp::DupOut,
+            Some('>') if self.chars.next_if_eq(&'|').is_some() => RedirOp::Clobber,
+            Some('>') => RedirOp::Out,
+            _ if self.chars.next_if_eq(&'&').is_some() => RedirOp::DupIn,
+            _ if self.chars.next_if_eq(&'>').is_some() => RedirOp::ReadWrite,
+            _ if self.chars.next_if_eq(&'<').is_none() => RedirOp::In,
+            _ if self.chars.next_if_eq(&'<').is_some() => RedirOp::HereString,
+            _ => RedirOp::HereDoc {
+                strip_tabs: self.chars.next_if_eq(&'-').is_some(),
+            },
+        };
+        Token::Redirect(fd, op)
+    }
+
+    /// Reads the body of a here-document up to the line with its delimiter. Unless part of
+    /// the delimiter was quoted, the body is expanded like text in double quotes, except
+    /// that a `"` is an ordinary character.
+    fn here_doc(&mut self, delimiter: &Word, strip_tabs: bool) -> Result<Word> {
+        let end = Token::Word(delimiter.clone()).to_string();
+        let mut body = String::new();
+        loop {
+            let mut line = String::new();
+            while let Some(c) = self.chars.next_if(|c| *c != '\n') {
+                line.push(c);
+            }
+            let newline = self.chars.next().is_some();
+            let line = if strip_tabs {
+                line.trim_start_matches('\t')
+            } else {
+                &line
+            };
+            if line == end {
+                break;
+            }
+            if !newline {
+                return Err(ShellError::HereDocEof(end));
+            }
+            body.push_str(line);
+            body.push('\n');
+        }
+        let quoted = delimiter.parts.iter().any(|part| {
+            matches!(
+                part,
+                WordPart::Text { quoted: true, .. }
+                    | WordPart::Param { quoted: true, .. }
+                    | WordPart::Command { quoted: true, .. }
+            )
+        });
+        let mut word = Word::default();
+        if quoted {
+            word.parts.push(WordPart::Text {
+                text: body,
+                quoted: true,
+            });
+            return Ok(word);
+        }
+        let mut lexer = Lexer::new(&body);
+        while let Some(c) = lexer.chars.next() {
+            match c {
+                '\\' => match lexer.chars.next() {
+                    Some('\n') => {}
+                    Some(c @ ('\\' | '$' | '`')) => word.push_char(c, true),
+                    Some(c) => {
+                        word.push_char('\\', true);
+                        word.push_char(c, true);
+                    }
+                    None => word.push_char('\\', true),
+                },
+                '$' => lexer.dollar(&mut word, true)?,
+                '`' => word.parts.push(WordPart::Command {
+                    source: lexer.backquoted(true)?,
+                    quoted: true,
+                }),
+                c => word.push_char(c, true),
+            }
+        }
+        Ok(word)
+    }
+
+    fn is_word_end(&self, c: char) -> bool {
+        matches!(
+            c,
+            ' ' | '\t' | '\n' | '|' | ';' | '&' | '>' | '<' | '(' | ')'
+        )
+    }
+
+    fn word(&mut self) -> Result<Token> {
+        let mut word = Word::default();
+        while let Some(&c) = self.chars.peek() {
+            if self.is_word_end(c) {
+                break;
+            }
+            self.chars.next();
+            match c {
+                '\\' => match self.chars.next() {
+                    // an escaped newline joins the lines, so one that ends the source waits
+                    // for the next line
+                    Some('\n') if self.chars.peek().is_none() => {
+                        return Err(ShellError::UnexpectedEof)
+                    }
+                    Some('\n') => {}
+                    Some(c) => word.push_char(c, true),
+                    // the line continues after a trailing backslash
+                    None => return Err(ShellError::UnexpectedEof),
+                },
+                '\'' => self.single_quoted(&mut word)?,
+                '"' => self.double_quoted(&mut word)?,
+                '$' => self.dollar(&mut word, false)?,
+                '`' => word.parts.push(WordPart::Command {
+                    source: self.backquoted(false)?,
+                    quoted: false,
+                }),
+                c => word.push_char(c, false),
+            }
+        }
+        // a number right in front of a redirection is the file descriptor it applies to
+        if matches!(self.chars.peek(), Some('>' | '<')) {
+            if let Some(fd) = word.as_unquoted().and_then(|w| w.parse().ok()) {
+                return Ok(self.redirect(Some(fd)));
+            }
+        }
+        Ok(Token::Word(word))
+    }
+
+    fn single_quoted(&mut self, word: &mut Word) -> Result<()> {
+        let start = word.parts.len();
+        loop {
+            match self.chars.next() {
+                Some('\'') => break,
+                Some(c) => word.push_char(c, true),
+                None => return Err(ShellError::Unmatched('\'')),
+            }
+        }
+        // keeps `''` as an (empty) quoted part
+        if word.parts.len() == start {
+            word.parts.push(WordPart::Text {
+                text: String::new(),
+                quoted: true,
+            });
+        }
+        Ok(())
+    }
+
+    fn double_quoted(&mut self, word: &mut Word) -> Result<()> {
+        let start = word.parts.len();
+        loop {
+            match self.chars.next() {
+                Some('"') => break,
+                Some('\\') => match self.chars.next() {
+                    Some('\n') => {}
+                    Some(c @ ('\\' | '"' | '$' | '`')) => word.push_char(c, true),
+                    Some(c) => {
+                        word.push_char('\\', true);
+                        word.push_char(c, true);
+                    }
+                    None => return Err(ShellError::Unmatched('"')),
+                },
+                Some('$') => self.dollar(word, true)?,
+                Some('`') => word.parts.push(WordPart::Command {
+                    source: self.backquoted(true)?,
+                    quoted: true,
+                }),
+                Some(c) => word.push_char(c, true),
+                None => return Err(ShellError::Unmatched('"')),
+            }
+        }
+        if word.parts.len() == start {
+            word.parts.push(WordPart::Text {
+                text: String::new(),
+                quoted: true,
+            });
+        }
+        Ok(())
+    }
+
+    /// Reads what follows a `$`. A `$` that starts no expansion is kept as text.
+    fn dollar(&mut self, word: &mut Word, quoted: bool) -> Result<()> {
+        let param = if self.chars.next_if_eq(&'(').is_some() {
+            let source = self.command_source()?;
+            word.parts.push(WordPart::Command { source, quoted });
+            return Ok(());
+        } else if self.chars.next_if_eq(&'{').is_some() {
+            self.braced()?
+        } else if let Some(name) = self.param_name(false) {
+            Param { name, op: None }
+        } else {
+            word.push_char('$', quoted);
+            return Ok(());
+        };
+        word.parts.push(WordPart::Param { param, quoted });
+        Ok(())
+    }
+
+    /// Reads the source of a `$(...)` command substitution up to the matching parenthesis.
+    fn command_source(&mut self) -> Result<String> {
+        let mut source = String::new();
+        let mut depth = 0;
+        let unmatched = || ShellError::Unmatched(')');
+        loop {
+            let c = self.chars.next().ok_or_else(unmatched)?;
+            match c {
+                '(' => depth += 1,
+                ')' if depth == 0 => return Ok(source),
+                ')' => depth -= 1,
+                '\\' => {
+                    source.push(c);
+                    source.push(self.chars.next().ok_or_else(unmatched)?);
+                    continue;
+                }
+                '\'' | '"' => {
+                    source.push(c);
+                    loop {
+                        let q = self.chars.next().ok_or_else(unmatched)?;
+                        source.push(q);
+                        if q == c {
+                            break;
+                        }
+                        if q == '\\' && c == '"' {
+                            source.push(self.chars.next().ok_or_else(unmatched)?);
+                        }
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+            source.push(c);
+        }
+    }
+
+    /// Reads the source of a `` `...` `` command substitution. A backslash in it only escapes
+    /// `$`, `` ` ``, `\` and, within double quotes, `"`.
+    fn backquoted(&mut self, in_d_quote: bool) -> Result<String> {
+        let mut source = String::new();
+        loop {
+            match self.chars.next() {
+                Some('`') => return Ok(source),
+                Some('\\') => match self.chars.next() {
+                    Some(c @ ('$' | '`' | '\\')) => source.push(c),
+                    Some('"') if in_d_quote => source.push('"'),
+                    Some(c) => {
+                        source.push('\\');
+                        source.push(c);
+                    }
+                    None => return Err(ShellError::Unmatched('`')),
+                },
+                Some(c) => source.push(c),
+                None => return Err(ShellError::Unmatched('`')),
+            }
+        }
+    }
+
+    /// Reads the name of a parameter. Without braces a positional parameter has one digit.
+    fn param_name(&mut self, braced: bool) -> Option<String> {
+        let &c = self.chars.peek()?;
+        let mut name = String::new();
+        if c.is_ascii_alphabetic() || c == '_' {
+            while let Some(c) = self
+                .chars
+                .next_if(|c| c.is_ascii_alphanumeric() || *c == '_')
+            {
+                name.push(c);
+            }
+        } else if c.is_ascii_digit() && braced {
+            while let Some(c) = self.chars.next_if(char::is_ascii_digit) {
+                name.push(c);
+            }
+        } else if c.is_ascii_digit() || "?$#!@*-".contains(c) {
+            self.chars.next();
+            name.push(c);
+        } else {
+            return None;
+        }
+        Some(name)
+    }
+
+    /// Reads the rest of a `${...}` expansion.
+    fn braced(&mut self) -> Result<Param> {
+        let rest = self.chars.clone();
+        let bad_substitution = || {
+            let inner: String = rest.clone().take_while(|c| *c != '}').collect();
+            ShellError::BadSubstitution(format!("${{{inner}}}"))
+        };
+        if self.chars.peek() == Some(&'#') && !matches!(self.peek_second(), Some('}') | None) {
+            self.chars.next();
+            let name = self.param_name(true).ok_or_else(bad_substitution)?;
+            if self.chars.next() != Some('}') {
+                return Err(bad_substitution());
+            }
+            return Ok(Param {
+                name,
+                op: Some(ParamOp::Length),
+            });
+        }
+        let name = self.param_name(true).ok_or_else(bad_substitution)?;
+        let colon = self.chars.next_if_eq(&':').is_some();
+        let op = match self.chars.next() {
+            Some('}') if !colon => None,
+            Some('-') => Some(ParamOp::Default {
+                colon,
+                word: self.operand(&['}'])?,
+            }),
+            Some('=') => Some(ParamOp::Assign {
+                colon,
+                word: self.operand(&['}'])?,
+            }),
+            Some('?') => Some(ParamOp::Error {
+                colon,
+                word: self.operand(&['}'])?,
+            }),
+            Some('+') => Some(ParamOp::Alternate {
+                colon,
+                word: self.operand(&['}'])?,
+            }),
+            Some('#') if !colon => Some(ParamOp::RemovePrefix {
+                longest: self.chars.next_if_eq(&'#').is_some(),
+                pattern: self.operand(&['}'])?,
+            }),
+            Some('%') if !colon => Some(ParamOp::RemoveSuffix {
+                longest: self.chars.next_if_eq(&'%').is_some(),
+                pattern: self.operand(&['}'])?,
+            }),
+            Some('/') if !colon => {
+                let mode = match self.chars.peek() {
+                    Some('/') => ReplaceMode::All,
+                    Some('#') => ReplaceMode::Prefix,
+                    Some('%') => ReplaceMode::Suffix,
+                    _ => ReplaceMode::First,
+                };
+                if mode != ReplaceMode::First {
+                    self.chars.next();
+                }
+                let pattern = self.operand(&['/', '}'])?;
+                let replacement = if self.chars.next_if_eq(&'/').is_some() {
+                    self.operand(&['}'])?
+                } else {
+                    Word::default()
+                };
+                Some(ParamOp::Replace {
+                    mode,
+                    pattern,
+                    replacement,
+                })
+            }
+            None => return Err(ShellError::Unmatched('}')),
+            _ => return Err(bad_substitution()),
+        };
+        if op.is_some() && self.chars.next() != Some('}') {
+            return Err(ShellError::Unmatched('}'));
+        }
+        Ok(Param { name, op })
+    }
+
+    /// Reads the word inside of `${...}` up to one of `ends`, which is left unread. Its text
+    /// counts as unquoted, even when the expansion itself is quoted.
+    fn operand(&mut self, ends: &[char]) -> Result<Word> {
+        let mut word = Word::default();
+        let mut depth = 0;
+        loop {
+            let Some(&c) = self.chars.peek() else {
+                return Err(ShellError::Unmatched('}'));
+            };
+            if depth == 0 && ends.contains(&c) {
+                return Ok(word);
+            }
+            self.chars.next();
+            match c {
+                '\\' => match self.chars.next() {
+                    Some('\n') => {}
+                    Some(c) => word.push_char(c, true),
+                    None => return Err(ShellError::Unmatched('}')),
+                },
+                '\'' => self.single_quoted(&mut word)?,
+                '"' => self.double_quoted(&mut word)?,
+                '$' => self.dollar(&mut word, false)?,
+                '`' => word.parts.push(WordPart::Command {
+                    source: self.backquoted(false)?,
+                    quoted: false,
+                }),
+                '{' => {
+                    depth += 1;
+                    word.push_char(c, false);
+                }
+                '}' => {
+                    depth -= 1;
+                    word.push_char(c, false);
+                }
+                c => word.push_char(c, false),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(input: &str) -> Vec<Token> {
+        Lexer::new(input).tokenize().unwrap()
+    }
+
+    fn text(text: &str, quoted: bool) -> WordPart {
+        WordPart::Text {
+            text: text.into(),
+            quoted,
+        }
+    }
+
+    fn word(parts: Vec<WordPart>) -> Token {
+        Token::Word(Word { parts })
+    }
+
+    #[test]
+    fn single_quotes_keep_everything() {
+        assert_eq!(
+            tokens(r#"'a $b \c "d"'"#),
+            [word(vec![text(r#"a $b \c "d""#, true)])]
+        );
+    }
+
+    #[test]
+    fn double_quotes_escape_only_some_characters() {
+        assert_eq!(
+            tokens(r#""a\"b\$c\\d\e""#),
+            [word(vec![text(r#"a"b$c\d\e"#, true)])]
+        );
+    }
+
+    #[test]
+    fn quoted_parts_join_one_word() {
+        assert_eq!(
+            tokens(r#"a'b c'"d"\ e"#),
+            [word(vec![
+                text("a", false),
+                text("b cd", true),
+                text(" ", true),
+                text("e", false)
+            ])]
+        );
+    }
+
+    #[test]
+    fn empty_quotes_make_a_quoted_part() {
+        let param = WordPart::Param {
+            param: Param {
+                name: "x".into(),
+                op: None,
+            },
+            quoted: false,
+        };
+        for input in ["$x''", "$x\"\""] {
+            assert_eq!(
+                tokens(input),
+                [word(vec![param.clone(), text("", true)])],
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn escaped_newline_joins_lines() {
+        assert_eq!(
+            tokens("echo a\\\nb"),
+            [
+                word(vec![text("echo", false)]),
+                word(vec![text("ab", false)])
+            ]
+        );
+        assert_eq!(
+            tokens("echo \"a\\\nb\""),
+            [
+                word(vec![text("echo", false)]),
+                word(vec![text("ab", true)])
+            ]
+        );
+    }
+
+    #[test]
+    fn trailing_backslash_waits_for_the_next_line() {
+        for input in ["echo a\\", "echo a\\\n", "echo \\\n"] {
+            let res = Lexer::new(input).tokenize();
+            assert!(matches!(res, Err(ShellError::UnexpectedEof)), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn unclosed_quotes() {
+        let res = Lexer::new("echo 'a").tokenize();
+        assert!(matches!(res, Err(ShellError::Unmatched('\''))));
+        let res = Lexer::new("echo \"a").tokenize();
+        assert!(matches!(res, Err(ShellError::Unmatched('"'))));
+    }
+
+    #[test]
+    fn operators_end_words() {
+        let a = || word(vec![text("a", false)]);
+        assert_eq!(
+            tokens("a|a&&a||a;a&"),
+            [
+                a(),
+                Token::Op(Op::Pipe),
+                a(),
+                Token::Op(Op::And),
+                a(),
+                Token::Op(Op::Or),
+                a(),
+                Token::Op(Op::Semi),
+                a(),
+                Token::Op(Op::Background),
+            ]
+        );
+    }
+
+    #[test]
+    fn number_before_redirection_is_its_descriptor() {
+        assert_eq!(
+            tokens("2>f"),
+            [
+                Token::Redirect(Some(2), RedirOp::Out),
+                word(vec![text("f", false)])
+            ]
+        );
+        assert_eq!(
+            tokens("a2>f")[0],
+            word(vec![text("a2", false)]),
+            "only a word of digits is a descriptor"
+        );
+    }
+}
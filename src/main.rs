@@ -1,210 +1,484 @@
 #[allow(unused_imports)]
 use std::io::{self, Write};
 use std::{
-    collections::HashSet,
+    collections::HashMap,
+    ffi::OsString,
     fmt::Display,
-    fs::{self, File, OpenOptions},
+    fs::{self, File},
+    os::{
+        fd::{AsRawFd, FromRawFd},
+        unix::{ffi::OsStringExt, fs::PermissionsExt, process::CommandExt},
+    },
     path::{Path, PathBuf},
+    process,
+    rc::Rc,
     str::FromStr,
 };
 use termion::{event::Key, input::TermRead, raw::IntoRawMode};
 use thiserror::Error;
 
+use ast::{AndOr, Connector, If, List, Pipeline, SimpleCommand};
+use complete::{Completion, Specs};
+use editor::{Action, Editor};
+use history::History;
+use jobs::{Group, Job, Jobs};
+use lexer::RedirOp;
+use menu::{Menu, Selection};
+use options::Options;
+use redirect::{Fds, Mode, Redirections, Target};
+use search::{Search, Step};
+use vars::{quote, Variables};
+
+mod ast;
+mod complete;
+mod editor;
+mod expand;
+mod functions;
+mod glob;
+mod histexpand;
+mod history;
+mod jobs;
+mod lexer;
+mod menu;
+mod options;
+mod parser;
+mod pattern;
+mod redirect;
+mod search;
+mod signals;
+mod vars;
+
 fn main() -> Result<()> {
+    let mut args = std::env::args();
+    let mut shell = Shell::new(args.next().unwrap_or_default());
+    let args: Vec<String> = args.collect();
+    match args.first().map(String::as_str) {
+        Some("-c") => {
+            let Some(source) = args.get(1) else {
+                eprintln!("{}: -c: option requires an argument", shell.arg0);
+                process::exit(2);
+            };
+            // `-c source name args...` sets `$0` and the positional parameters
+            if let Some(name) = args.get(2) {
+                shell.arg0 = name.clone();
+                shell.positional = args[3..].to_vec();
+            }
+            let mut lines = source.split_inclusive('\n').map(String::from);
+            run_script(shell, || Ok(lines.next()))
+        }
+        Some(path) => {
+            let source = match fs::read_to_string(path) {
+                Ok(source) => source,
+                Err(e) => {
+                    eprintln!("{}: {path}: {}", shell.arg0, error_message(&e));
+                    process::exit(127);
+                }
+            };
+            shell.arg0 = path.into();
+            shell.positional = args[1..].to_vec();
+            let mut lines = source.split_inclusive('\n').map(String::from);
+            run_script(shell, || Ok(lines.next()))
+        }
+        None if unsafe { libc::isatty(0) } == 0 => run_script(shell, read_line),
+        None => interactive(shell),
+    }
+}
+
+/// Runs commands as they are read, one line at a time, and exits with the status of the
+/// last one. A syntax error ends the script. Errors go to stderr after `$0`.
+fn run_script(mut shell: Shell, mut next_line: impl FnMut() -> Result<Option<String>>) -> ! {
+    let mut source = String::new();
+    loop {
+        let line = match next_line() {
+            Ok(line) => line,
+            Err(e) => {
+                eprintln!("{}: {e}", shell.arg0);
+                process::exit(1);
+            }
+        };
+        let line = match line {
+            Some(line) => line,
+            // a backslash-newline at the very end has no line to join and is dropped
+            None if source.ends_with("\\\n") => {
+                source.truncate(source.len() - 2);
+                String::new()
+            }
+            None => break,
+        };
+        source.push_str(&line);
+        match parser::parse(&source) {
+            Err(e) if e.is_incomplete() => continue,
+            Err(e) => {
+                eprintln!("{}: {e}", shell.arg0);
+                process::exit(2);
+            }
+            Ok(list) => match list.run(&mut shell).and_then(|status| {
+                shell.notify()?;
+                Ok(status)
+            }) {
+                Ok(status) => shell.status = status,
+                Err(ShellError::Exit(code)) => process::exit(code),
+                Err(e) => {
+                    eprintln!("{}: {e}", shell.arg0);
+                    process::exit(1);
+                }
+            },
+        }
+        source.clear();
+    }
+    if let Err(e) = parser::parse(&source) {
+        eprintln!("{}: {e}", shell.arg0);
+        process::exit(2);
+    }
+    process::exit(shell.status)
+}
+
+/// Reads a line of stdin without reading ahead, so that the commands of a script piped to
+/// the shell can read the lines that follow them.
+fn read_line() -> Result<Option<String>> {
+    let mut line = Vec::new();
+    let mut byte = 0u8;
+    loop {
+        match unsafe { libc::read(0, (&mut byte as *mut u8).cast(), 1) } {
+            -1 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(err.into());
+                }
+            }
+            0 => break,
+            _ => {
+                line.push(byte);
+                if byte == b'\n' {
+                    break;
+                }
+            }
+        }
+    }
+    if line.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+}
+
+fn interactive(mut shell: Shell) -> Result<()> {
+    shell.pgid = jobs::init();
+    shell.job_control = true;
+    shell.options.histexpand = true;
+    shell.options.emacs = true;
+    shell.load_history();
     let mut std_out = io::stdout().into_raw_mode()?;
     let stdin = io::stdin();
+    let mut editor = Editor::default();
     writeln!(std_out, "\n\r{}", std::env::current_dir()?.display())?;
-    write!(std_out, "\r$ ")?;
-    std_out.flush()?;
+    editor.start("$ ".into());
+    editor.redraw(&mut std_out)?;
+    // the lines of a command that continues on the line being edited
     let mut input = String::new();
-    let mut display_possibilities = false;
-    let mut completions = Vec::default();
-    let mut in_d_quote = false;
-    let mut in_quote = false;
-    std_out.flush()?;
+    let mut search: Option<Search> = None;
+    let mut menu: Option<Menu> = None;
+    // completions too many to show without asking
+    let mut asking: Option<Vec<Completion>> = None;
     for k in stdin.keys() {
-        match k.as_ref().unwrap() {
+        let key = k?;
+        if let Some(completions) = asking.take() {
+            editor.set_below(String::new());
+            match key {
+                Key::Char('y' | 'Y' | ' ') => print_list(&mut editor, &completions, &mut std_out)?,
+                _ => editor.redraw(&mut std_out)?,
+            }
+            continue;
+        }
+        if let Some(current) = &mut menu {
+            match current.key(&key) {
+                Selection::Moved => {
+                    current.show(&mut editor);
+                    editor.redraw(&mut std_out)?;
+                    continue;
+                }
+                Selection::Accept { pass } => {
+                    menu.take().unwrap().accept(&mut editor);
+                    editor.redraw(&mut std_out)?;
+                    if !pass {
+                        continue;
+                    }
+                }
+                Selection::Cancel { pass } => {
+                    menu.take().unwrap().cancel(&mut editor);
+                    editor.redraw(&mut std_out)?;
+                    if !pass {
+                        continue;
+                    }
+                }
+            }
+        }
+        if let Some(current) = &mut search {
+            let entries = shell.history.entries();
+            match current.key(&key, entries) {
+                Step::Searching => {
+                    current.show(&mut editor, entries);
+                    editor.redraw(&mut std_out)?;
+                    continue;
+                }
+                Step::Accept { pass } => {
+                    search.take().unwrap().accept(&mut editor, entries);
+                    editor.redraw(&mut std_out)?;
+                    if !pass {
+                        continue;
+                    }
+                }
+                Step::Cancel { pass } => {
+                    search.take().unwrap().cancel(&mut editor);
+                    editor.redraw(&mut std_out)?;
+                    if !pass {
+                        continue;
+                    }
+                }
+            }
+        }
+        let key = match editor.key(&key) {
+            Action::Edited => {
+                editor.redraw(&mut std_out)?;
+                continue;
+            }
+            Action::Pass(key) => key,
+            // the edited line is run as if it had been typed
+            Action::Edit => {
+                std_out.suspend_raw_mode()?;
+                let edited = edit_line(&mut shell, editor.line());
+                std_out.activate_raw_mode()?;
+                let edited = match edited {
+                    Ok(edited) => edited,
+                    // the line is kept, under the error
+                    Err(e) => {
+                        let cursor = editor.cursor();
+                        editor.finish(&mut std_out)?;
+                        eprint!("{e}\r\n");
+                        writeln!(std_out, "\n\r{}", std::env::current_dir()?.display())?;
+                        editor.set_cursor(cursor);
+                        editor.redraw(&mut std_out)?;
+                        continue;
+                    }
+                };
+                match edited {
+                    Some(line) => {
+                        editor.set_line(line);
+                        Key::Char('\n')
+                    }
+                    None => {
+                        editor.redraw(&mut std_out)?;
+                        continue;
+                    }
+                }
+            }
+        };
+        match key {
             Key::Char('\n') => {
-                if in_quote || in_d_quote {
-                    //TODO
-                    //write!(std_out, " ")?;
-                    //std_out.flush()?;
+                editor.finish(&mut std_out)?;
+                let mut line = editor.line().to_string();
+                if shell.options.histexpand {
+                    // the expanded line is shown, and a line that does not expand is dropped
+                    match histexpand::expand(shell.history.entries(), &line) {
+                        Ok(expanded) if expanded != line => {
+                            write!(std_out, "{}\r\n", expanded.replace('\n', "\r\n"))?;
+                            line = expanded;
+                        }
+                        Ok(_) => {}
+                        Err(e) => {
+                            writeln!(std_out, "{e}\r")?;
+                            writeln!(std_out, "\n\r{}", std::env::current_dir()?.display())?;
+                            editor.start("$ ".into());
+                            editor.redraw(&mut std_out)?;
+                            input.clear();
+                            shell.history.reset();
+                            continue;
+                        }
+                    }
+                }
+                input.push_str(&line);
+                let parsed = parser::parse(&input);
+                if parsed.as_ref().is_err_and(ShellError::is_incomplete) {
+                    input.push('\n');
+                    editor.start(shell.var("PS2").unwrap_or("> ".into()));
+                    editor.redraw(&mut std_out)?;
                     continue;
                 }
-                writeln!(std_out, "\r")?;
-                match Command::from_str(&input) {
-                    Err(ShellError::NotImplemented(e)) => println!("\r{e}: command not found"),
-                    Err(ShellError::UnknownType(t)) => println!("\r{t}: not found"),
-                    Err(ShellError::Exit(code)) => match code.as_str() {
-                        "0" => return Ok(()),
-                        _ => return Err(ShellError::Exit(code)),
-                    },
+                shell.add_history(&input);
+                // commands get the terminal in its normal mode
+                std_out.suspend_raw_mode()?;
+                shell.status = match parsed {
+                    Err(
+                        e @ (ShellError::Syntax(_)
+                        | ShellError::Unmatched(_)
+                        | ShellError::BadSubstitution(_)
+                        | ShellError::UnexpectedEof),
+                    ) => {
+                        eprintln!("{}: {e}", shell.arg0);
+                        2
+                    }
                     Err(e) => return Err(e),
-                    Ok(c) => match c.execute() {
-                        Ok(()) => {}
-                        Err(ShellError::IO(_)) => {
-                            println!("\r{}: No such file or directory", c)
-                        }
-                        Err(e) => println!("\r{:#?}", e),
+                    Ok(c) => match c.run(&mut shell) {
+                        // the terminal is not in raw mode here, nothing needs restoring
+                        Err(ShellError::Exit(code)) => std::process::exit(code),
+                        status => status?,
                     },
-                }
+                };
+                shell.notify()?;
+                editor.set_vi(shell.options.vi);
+                std_out.activate_raw_mode()?;
                 writeln!(std_out, "\n\r{}", std::env::current_dir()?.display())?;
-                write!(std_out, "\r$ ")?;
-                std_out.flush()?;
+                editor.start("$ ".into());
+                editor.redraw(&mut std_out)?;
                 input.clear();
             }
-            Key::Char('\'') => {
-                input.push('\'');
-                write!(std_out, "\'")?;
-                std_out.flush()?;
-                // if !in_d_quote {
-                // in_quote = !in_quote;
-                // }
-            }
-            Key::Char('\"') => {
-                input.push('\"');
-                write!(std_out, "\"")?;
-                std_out.flush()?;
-                // in_d_quote = !in_d_quote;
-            }
-            Key::Backspace => {
-                if !input.is_empty() {
-                    if let Some(c) = input.pop() {
-                        if c == '\'' {
-                            in_quote = !in_quote;
-                        } else if c == '\"' {
-                            in_d_quote = !in_d_quote;
-                        }
-                    }
-                    write!(std_out, "\r$ {} \r$ {}", input, input)?;
-                    std_out.flush()?;
-                }
+            // discards the command being typed, continuation lines included
+            Key::Ctrl('c') => {
+                editor.cancel(&mut std_out)?;
+                writeln!(std_out, "\n\r{}", std::env::current_dir()?.display())?;
+                editor.start("$ ".into());
+                editor.redraw(&mut std_out)?;
+                input.clear();
+                shell.history.reset();
+                shell.status = 130;
             }
-            Key::Char('\t') => {
-                if display_possibilities {
-                    print_list(&completions)?;
-                    /*
-                    let common_prefix = common_prefix(&input, &completions);
-                    input.push_str(&common_prefix);
-                    */
-                    writeln!(std_out, "\n\r{}", std::env::current_dir()?.display())?;
-                    write!(std_out, "\r$ {}", input)?;
-                    std_out.flush()?;
-
-                    display_possibilities = false;
-                    completions.clear();
-                    continue;
+            Key::Ctrl(c @ ('r' | 's')) => {
+                let current = Search::new(&editor, c == 'r');
+                current.show(&mut editor, shell.history.entries());
+                editor.redraw(&mut std_out)?;
+                search = Some(current);
+            }
+            // the line being typed is kept while going through the history
+            Key::Up => {
+                if let Some(entry) = shell.history.up(editor.line()) {
+                    editor.set_line(entry.into());
+                    editor.redraw(&mut std_out)?;
                 }
-                if input.is_empty() {
-                    continue;
+            }
+            Key::Down => {
+                if let Some(entry) = shell.history.down() {
+                    editor.set_line(entry.into());
+                    editor.redraw(&mut std_out)?;
                 }
-                let a_completions = auto_complete(&input)?;
-                match a_completions.len() {
+            }
+            Key::Char('\t') => {
+                // spec files and completers run like commands, with the terminal in its normal mode
+                std_out.suspend_raw_mode()?;
+                let completed = shell.completions(editor.line(), editor.cursor());
+                std_out.activate_raw_mode()?;
+                // a completion that fails has nothing to offer, which rings the bell
+                let (range, completions) = completed.unwrap_or_default();
+                match completions.len() {
                     1 => {
-                        input = a_completions.first().unwrap().clone();
-                        write!(std_out, "\r$ {} \r$ {}", input, input)?;
-                        std_out.flush()?;
+                        editor.replace(range, &completions[0].text);
+                        editor.redraw(&mut std_out)?;
                     }
                     0 => {
                         write!(std_out, "{}", 7 as char)?;
                         std_out.flush()?;
                     }
-                    _ => {
-                        completions = a_completions;
-                        let common_prefix = common_prefix(&input, &completions);
-                        if common_prefix.is_empty() {
-                            write!(std_out, "{}", 7 as char)?;
-                            std_out.flush()?;
-                            display_possibilities = true;
+                    // what the completions share is filled in first, then they are shown
+                    n => {
+                        let common_prefix = complete::common_prefix(&completions).to_string();
+                        if common_prefix.len() > range.len() {
+                            editor.replace(range, &common_prefix);
+                        } else if n > menu::QUERY_ITEMS {
+                            editor.set_below(format!("Display all {n} possibilities? (y or n)"));
+                            asking = Some(completions);
+                        } else if !menu::fits(&completions) {
+                            print_list(&mut editor, &completions, &mut std_out)?;
                             continue;
+                        } else {
+                            let current = Menu::new(completions, range, &editor);
+                            current.show(&mut editor);
+                            menu = Some(current);
                         }
-                        input.push_str(&common_prefix);
-                        writeln!(std_out, "\n\r{}", std::env::current_dir()?.display())?;
-                        write!(std_out, "\r$ {}", input)?;
-                        std_out.flush()?;
-                        completions.clear();
+                        editor.redraw(&mut std_out)?;
                     }
                 }
             }
-            Key::Char(c) => {
-                write!(std_out, "{}", c)?;
-                std_out.flush()?;
-                input.push(*c)
-            }
             _ => {}
         }
     }
     Ok(())
 }
 
-fn print_list(completions: &[String]) -> Result<()> {
-    for chunk in completions.chunks(3) {
-        println!();
-        print!("\r");
-        for c in chunk {
-            print!("{} ", c);
+/// Edits a line in `$VISUAL` or `$EDITOR`, `vi` if neither is set. Returns the edited line,
+/// or `None` if the editor failed.
+fn edit_line(shell: &mut Shell, line: &str) -> Result<Option<String>> {
+    let (mut file, path) = temp_file("shell-edit-")?;
+    let edited = (|| {
+        file.write_all(format!("{line}\n").as_bytes())?;
+        drop(file);
+        let editor = shell
+            .var("VISUAL")
+            .or_else(|| shell.var("EDITOR"))
+            .unwrap_or("vi".into());
+        let command = format!("{editor} {}", vars::quote_word(&path.to_string_lossy()));
+        if parser::parse(&command)?.run(shell)? != 0 {
+            return Ok(None);
         }
-    }
-    println!();
-    Ok(())
+        Ok(Some(
+            fs::read_to_string(&path)?.trim_end_matches('\n').into(),
+        ))
+    })();
+    _ = fs::remove_file(&path);
+    edited
 }
 
-fn common_prefix(input: &str, completions: &[String]) -> String {
-    let mut common: String = completions
-        .first()
-        .unwrap()
-        .strip_prefix(input)
-        .unwrap()
-        .into();
-    for c in &completions[1..] {
-        let suffix = c.strip_prefix(input).unwrap();
-        for len in (0..suffix.len().min(common.len() + 1)).rev() {
-            if common.starts_with(&suffix[..len]) || common == suffix[..len] {
-                common = suffix[..len].into();
-                break;
-            }
-        }
-        if common.is_empty() {
-            return "".into();
-        }
+/// Creates a file only the user can read, with a random name starting with `prefix` in the
+/// temporary directory, failing rather than opening one that is already there.
+fn temp_file(prefix: &str) -> Result<(File, PathBuf)> {
+    let template = std::env::temp_dir().join(format!("{prefix}XXXXXX"));
+    let display = template.to_string_lossy().into_owned();
+    let mut template = template.into_os_string().into_vec();
+    template.push(0);
+    let fd = unsafe { libc::mkostemp(template.as_mut_ptr().cast(), libc::O_CLOEXEC) };
+    if fd == -1 {
+        return Err(ShellError::Redirect(display, io::Error::last_os_error()));
     }
+    template.pop();
+    let path = PathBuf::from(OsString::from_vec(template));
+    Ok((unsafe { File::from_raw_fd(fd) }, path))
+}
 
-    common
+/// Prints completions under the line, for lists too long for the menu, and draws the line
+/// again under them.
+fn print_list(editor: &mut Editor, completions: &[Completion], out: &mut impl Write) -> Result<()> {
+    let cursor = editor.cursor();
+    editor.finish(out)?;
+    let (columns, _) = editor::size();
+    write!(
+        out,
+        "{}\r\n",
+        menu::grid(completions, columns, None).replace('\n', "\r\n")
+    )?;
+    writeln!(out, "\n\r{}", std::env::current_dir()?.display())?;
+    editor.set_cursor(cursor);
+    editor.redraw(out)?;
+    Ok(())
 }
 
-fn auto_complete(input: &str) -> Result<Vec<String>> {
-    let mut completions = Vec::new();
-    for s in ["echo ", "type ", "cd ", "exit "] {
-        if s.starts_with(input) {
-            completions.push(s.into());
-        }
-    }
-    if !completions.is_empty() {
-        return Ok(completions);
-    }
-    for s in std::env::var("PATH")?.split(":") {
-        match fs::read_dir(s) {
-            Err(_) => continue,
-            Ok(entries) => {
-                for entry in entries {
-                    let name = entry?.file_name();
-                    let name = name.to_string_lossy();
-                    if name.starts_with(input) {
-                        let mut name = name.into_owned();
-                        name.push(' ');
-                        completions.push(name);
-                    }
-                }
-            }
-        }
+type Result<T> = std::result::Result<T, ShellError>;
+
+impl ShellError {
+    /// Whether the error comes from input that ends before the command does, like an open
+    /// quote or a trailing `|`, so that reading more lines may complete it.
+    fn is_incomplete(&self) -> bool {
+        matches!(
+            self,
+            Self::Unmatched(_) | Self::UnexpectedEof | Self::HereDocEof(_)
+        )
     }
-    let completions: HashSet<String> = completions.into_iter().collect();
-    let mut completions: Vec<String> = completions.into_iter().collect();
-    completions.sort();
-    Ok(completions)
 }
 
-type Result<T> = std::result::Result<T, ShellError>;
+/// The message of an I/O error without the `(os error N)` that std appends to it.
+fn error_message(e: &io::Error) -> String {
+    let message = e.to_string();
+    match message.find(" (os error") {
+        Some(i) => message[..i].into(),
+        None => message,
+    }
+}
 
 #[derive(Error, Debug)]
 enum ShellError {
@@ -215,104 +489,133 @@ enum ShellError {
     #[error("{0}: command not found")]
     NotImplemented(String),
     #[error("exit code {0} != 0")]
-    Exit(String),
+    Exit(i32),
     #[error("type not known {0}")]
     UnknownType(String),
+    #[error("syntax error near unexpected token `{0}'")]
+    Syntax(String),
+    #[error("unexpected EOF while looking for matching `{0}'")]
+    Unmatched(char),
+    #[error("syntax error: unexpected end of file")]
+    UnexpectedEof,
+    #[error("here-document delimited by end-of-file (wanted `{0}')")]
+    HereDocEof(String),
+    #[error("{0}: {}", error_message(.1))]
+    Redirect(String, io::Error),
+    #[error("{0}: cannot overwrite existing file")]
+    Clobber(String),
+    #[error("{0}: ambiguous redirect")]
+    AmbiguousRedirect(String),
+    #[error("{0}: bad file descriptor")]
+    BadFd(u32),
+    #[error("{0}: bad substitution")]
+    BadSubstitution(String),
+    #[error("{0}: {1}")]
+    Param(String, String),
+    /// `${name?message}` of a parameter that is not set, which ends a shell that is not
+    /// interactive.
+    #[error("{0}: {1}")]
+    NotSet(String, String),
+    /// A program that was found but could not be started.
+    #[error("{0}: {}", error_message(.1))]
+    Exec(String, io::Error),
+    #[error("{0}: readonly variable")]
+    Readonly(String),
+    #[error("{0}: `{1}': not a valid identifier")]
+    InvalidName(&'static str, String),
+    #[error("{0}: {1}: invalid option")]
+    InvalidOption(&'static str, String),
+    #[error("{0}: event not found")]
+    EventNotFound(String),
+    #[error("{0}: bad word specifier")]
+    BadWordSpecifier(String),
+    #[error("{0}: substitution failed")]
+    SubstitutionFailed(String),
     #[error("Env error: {0}")]
     Env(#[from] std::env::VarError),
 }
 
-fn handle_input(mut input: &str) -> Vec<String> {
-    let mut args = Vec::new();
-    while !input.is_empty() {
-        if input.starts_with('\'') {
-            let (next_arg, next_input) = longest_sequence(&input[1..], true, false);
-            args.push(next_arg);
-            input = next_input;
-        } else if input.starts_with('\"') {
-            let (next_arg, next_input) = longest_sequence(&input[1..], false, true);
-            args.push(next_arg);
-            input = next_input;
-        } else {
-            let (next_arg, next_input) = longest_sequence(input, false, false);
-            args.push(next_arg);
-            input = next_input;
+/// State that outlives a single command line.
+struct Shell {
+    /// `$0`, the name of the shell or of the script it runs.
+    arg0: String,
+    /// `$1`, `$2` and so on.
+    positional: Vec<String>,
+    /// Exit status of the last command, `$?`.
+    status: i32,
+    vars: Variables,
+    options: Options,
+    /// Exit status of the last command substitution, which a command made only of
+    /// assignments returns.
+    last_substitution: Option<i32>,
+    /// Whether jobs get process groups and the terminal, which is only the case for an
+    /// interactive shell and not for its subshells.
+    job_control: bool,
+    /// The process group of the shell, which gets the terminal back from foreground jobs.
+    pgid: libc::pid_t,
+    /// The process group of the job whose processes are being started.
+    group: Option<Group>,
+    jobs: Jobs,
+    history: History,
+    specs: Specs,
+    /// The bodies of the functions defined, by name.
+    functions: HashMap<String, Rc<ast::Command>>,
+    /// The pid of the last process started in the background, `$!`.
+    last_background: Option<libc::pid_t>,
+}
+
+impl Shell {
+    fn new(arg0: String) -> Self {
+        Self {
+            arg0,
+            positional: Vec::new(),
+            status: 0,
+            vars: Variables::from_env(),
+            options: Options::default(),
+            last_substitution: None,
+            job_control: false,
+            pgid: 0,
+            group: None,
+            jobs: Jobs::default(),
+            history: History::default(),
+            specs: Specs::default(),
+            functions: HashMap::new(),
+            last_background: None,
         }
     }
 
-    args
-}
-fn longest_sequence(mut input: &str, mut in_quote: bool, mut in_d_quote: bool) -> (String, &str) {
-    let mut res = String::new();
-    loop {
-        let nxt_quote = input.find('\'').unwrap_or(input.len());
-        let nxt_wht = input.find(char::is_whitespace).unwrap_or(input.len());
-        let nxt_d_quote = input.find('\"').unwrap_or(input.len());
-        let nxt_bkslsh = input.find('\\').unwrap_or(input.len());
-        if nxt_bkslsh == input.len()
-            && nxt_d_quote == input.len()
-            && nxt_wht == input.len()
-            && nxt_quote == input.len()
-        {
-            res.push_str(input);
-            input = "";
-            return (res, input);
-        }
-        if in_d_quote {
-            if nxt_bkslsh < nxt_d_quote {
-                let (in_between, next_input) = input.split_at(nxt_bkslsh);
-                res.push_str(in_between);
-                let (c, next_input) = next_input.split_at(2);
-                let c = c.chars().last().unwrap();
-                if c == '\\' || c == '\"' || c == '$' || c == '\n' {
-                    res.push(c);
-                } else {
-                    res.push('\\');
-                    res.push(c);
-                }
-                input = next_input;
-                continue;
-            }
-            if nxt_d_quote < input.len() {
-                in_d_quote = false;
-                let (sub_s, next_input) = input.split_at(nxt_d_quote);
-                res.push_str(sub_s);
-                input = &next_input[1..];
-            } else {
-                //TODO
-                return (input.trim().replace('\"', ""), "");
-            }
-        } else if in_quote {
-            if nxt_quote < input.len() {
-                in_quote = false;
-                let (sub_s, next_input) = input.split_at(nxt_quote);
-                res.push_str(sub_s);
-                input = &next_input[1..];
-            } else {
-                //TODO
-                return (input.trim().replace('\'', ""), "");
-            }
-        } else if nxt_bkslsh < nxt_d_quote && nxt_bkslsh < nxt_quote && nxt_bkslsh < nxt_wht {
-            let (in_between, next_input) = input.split_at(nxt_bkslsh);
-            res.push_str(in_between);
-            let (c, next_input) = next_input.split_at(2);
-            res.push(c.chars().last().unwrap());
-            input = next_input;
-        } else if nxt_wht < nxt_quote && nxt_wht < nxt_d_quote {
-            let (r, next_in) = input.split_once(char::is_whitespace).unwrap_or(("", ""));
-            res.push_str(r);
-            return (res, next_in.trim_start());
-        } else if nxt_d_quote < nxt_quote {
-            in_d_quote = true;
-            let (in_between, next_input) = input.split_at(nxt_d_quote);
-            input = &next_input[1..];
-            res.push_str(in_between);
-        } else {
-            in_quote = true;
-            let (in_between, next_input) = input.split_at(nxt_quote);
-            input = &next_input[1..];
-            res.push_str(in_between);
+    fn var(&self, name: &str) -> Option<String> {
+        self.vars.get(name).map(Into::into)
+    }
+
+    fn set_var(&mut self, name: &str, value: String) -> Result<()> {
+        self.vars.set(name, value)
+    }
+
+    /// Looks a command up in `PATH`, skipping directories and files that cannot be executed.
+    /// Names containing a `/` are taken as paths, which fail to run if they are not programs.
+    fn find_in_path(&self, name: &str) -> Option<String> {
+        if name.contains('/') {
+            return fs::metadata(name).is_ok().then(|| name.into());
         }
+        self.var("PATH")
+            .unwrap_or_default()
+            .split(":")
+            .map(|path| format!("{}/{}", path, name))
+            .find(|path| {
+                fs::metadata(path).is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+            })
+    }
+}
+
+/// Resolves `~` or `~/` at the start of a path to the home directory.
+fn expand_tilde(p: &str) -> PathBuf {
+    if p == "~" || p.starts_with("~/") {
+        let home = std::env::home_dir().unwrap_or(Path::new("/").into());
+        let p = p.trim_start_matches("~").trim_start_matches("/");
+        home.join(PathBuf::from(p))
+    } else {
+        PathBuf::from_str(p).unwrap_or_default()
     }
 }
 
@@ -327,197 +630,37 @@ trait OutPut {
     fn write(&self, input: &str) -> Result<()>;
 }
 
-#[derive(Default, Debug)]
-struct StdOut {
-    to: Vec<(PathBuf, Mode)>,
-}
-#[derive(Default, Debug)]
-struct StdErr {
-    to: Vec<(PathBuf, Mode)>,
+/// Output of a builtin to one of its descriptors. `fds` are the command's redirections, which
+/// are opened once and shared by all of its writes.
+struct Output<'a> {
+    fd: u32,
+    fds: &'a Fds,
 }
 
-impl OutPut for StdOut {
-    fn write(&self, input: &str) -> Result<()> {
-        if self.to.is_empty() {
-            for line in input.lines() {
-                println!("\r{}", line);
-            }
-        }
-        for (f, mode) in &self.to {
-            let mut file = match mode {
-                Mode::Append => OpenOptions::new().append(true).create(true).open(f)?,
-                Mode::Overwrite => File::create(f)?,
-                Mode::Out => {
-                    for line in input.lines() {
-                        println!("\r{}", line);
-                    }
-                    continue;
-                }
-            };
-            file.write_all(input.as_bytes())?;
-        }
-        Ok(())
+impl<'a> Output<'a> {
+    fn new(fd: u32, fds: &'a Fds) -> Self {
+        Self { fd, fds }
     }
 }
 
-impl OutPut for StdErr {
+impl OutPut for Output<'_> {
     fn write(&self, input: &str) -> Result<()> {
-        if self.to.is_empty() {
-            print!("{}", input);
-        }
-        for (f, mode) in &self.to {
-            let mut file = match mode {
-                Mode::Append => OpenOptions::new().append(true).create(true).open(f)?,
-                Mode::Overwrite => File::create(f)?,
-                Mode::Out => {
-                    print!("{}", input);
-                    continue;
-                }
-            };
-            file.write_all(input.as_bytes())?;
+        match self.fds.get(&self.fd) {
+            Some(Some(file)) => (&*file).write_all(input.as_bytes())?,
+            Some(None) => return Err(ShellError::BadFd(self.fd)),
+            None if self.fd == 2 => eprint!("{}", input),
+            None => print!("{}", input),
         }
         Ok(())
     }
 }
 
-#[derive(Default, Debug)]
-enum Mode {
-    Append,
-    Overwrite,
-    #[default]
-    Out,
-}
-
-fn get_redirection(mut input: &str) -> (StdOut, StdErr, &str) {
-    let mut stderr = StdErr::default();
-    let mut stdout = StdOut::default();
-    let mut in_d_quote = false;
-    let mut in_quote = false;
-    let mut input_trimmed = false;
-    let mut i = 0;
-    let mut input_ = input.chars().peekable();
-    let old_input = input;
-    while let Some(c) = input_.next() {
-        i += 1;
-        match c {
-            '\"' => in_d_quote = !in_d_quote,
-            '\'' => in_quote = !in_quote,
-            '\\' => {
-                if let Some(n) = input_.peek() {
-                    if *n == '>' {
-                        _ = input_.next();
-                        i += 1;
-                    }
-                }
-            }
-            '>' => {
-                if in_quote || in_d_quote {
-                    continue;
-                }
-
-                if !input_trimmed {
-                    input = &input[..i - 1];
-                    input_trimmed = true;
-                }
-                if let Some(n) = input_.peek() {
-                    if *n == '>' {
-                        _ = input_.next();
-                        i += 1;
-                        stdout
-                            .to
-                            .push((get_file_name(old_input[i..].trim_start()), Mode::Append));
-                    } else {
-                        stdout
-                            .to
-                            .push((get_file_name(old_input[i..].trim_start()), Mode::Overwrite));
-                    }
-                }
-            }
-            '1' => {
-                if in_quote || in_d_quote {
-                    continue;
-                }
-                if let Some(n) = input_.peek() {
-                    if *n == '>' {
-                        if !input_trimmed {
-                            input = &input[..i - 1];
-                            input_trimmed = true;
-                        }
-                        _ = input_.next();
-                        i += 1;
-                        if let Some(n) = input_.peek() {
-                            if *n == '>' {
-                                _ = input_.next();
-                                i += 1;
-                                stdout.to.push((
-                                    get_file_name(old_input[i..].trim_start()),
-                                    Mode::Append,
-                                ));
-                            } else {
-                                stdout.to.push((
-                                    get_file_name(old_input[i..].trim_start()),
-                                    Mode::Overwrite,
-                                ));
-                            }
-                        }
-                    }
-                }
-            }
-            '2' => {
-                if in_quote || in_d_quote {
-                    continue;
-                }
-                if let Some(n) = input_.peek() {
-                    if *n == '>' {
-                        if !input_trimmed {
-                            input = &input[..i - 1];
-                            input_trimmed = true;
-                        }
-                        _ = input_.next();
-                        i += 1;
-                        if let Some(n) = input_.peek() {
-                            if *n == '>' {
-                                _ = input_.next();
-                                i += 1;
-                                stderr.to.push((
-                                    get_file_name(old_input[i..].trim_start()),
-                                    Mode::Append,
-                                ));
-                            } else {
-                                stderr.to.push((
-                                    get_file_name(old_input[i..].trim_start()),
-                                    Mode::Overwrite,
-                                ));
-                            }
-                        }
-                    }
-                }
-            }
-            _ => {}
-        }
-    }
-    (stdout, stderr, input)
-}
-
-fn get_file_name(input: &str) -> PathBuf {
-    //TODO error handling
-    let file_name = handle_input(input);
-    let p = file_name.first().unwrap();
-    let p: PathBuf = if p.starts_with("~") {
-        let home = std::env::home_dir().unwrap_or(Path::new("/").into());
-        let p = p.trim_start_matches("~").trim_start_matches("/");
-        home.join(PathBuf::from(p))
-    } else {
-        PathBuf::from_str(p).unwrap_or_default()
-    };
-    p //.canonicalize().unwrap()
-}
-
 #[derive(Default)]
 struct Args {
     args: Vec<String>,
-    out: StdOut,
-    err: StdErr,
+    /// Variables assigned in front of the command, which only its environment sees.
+    env: Vec<(String, String)>,
+    redirections: Redirections,
 }
 
 impl Args {
@@ -525,132 +668,733 @@ impl Args {
         self.args.append(&mut args);
         self
     }
-    fn with_stdout(mut self, out: StdOut) -> Self {
-        self.out = out;
+    fn with_env(mut self, env: Vec<(String, String)>) -> Self {
+        self.env = env;
         self
     }
-    fn with_stderr(mut self, err: StdErr) -> Self {
-        self.err = err;
+    fn with_redirections(mut self, redirections: Redirections) -> Self {
+        self.redirections = redirections;
         self
     }
+
+    /// The first argument, for builtins that take one.
+    fn arg(&self) -> Option<&str> {
+        self.args.first().map(String::as_str)
+    }
 }
 
+const BUILTINS: [&str; 15] = [
+    "echo", "type", "exit", "pwd", "cd", "export", "unset", "readonly", "set", "shift", "jobs",
+    "fg", "bg", "wait", "complete",
+];
+
 #[derive(Default)]
 enum Command {
     Echo(Args),
     #[default]
-    NoCommand,
-    Type(String, Option<String>),
+    Empty,
+    Type(Args),
     External(String, Args),
-    Pwd,
-    Cd(String),
+    Pwd(Args),
+    Cd(Args),
+    Exit(Args),
+    /// A command made of assignments only, which set shell variables.
+    Assign(Vec<(String, String)>),
+    Export(Args),
+    Readonly(Args),
+    Unset(Args),
+    Shift(Args),
+    Set(Args),
+    Jobs(Args),
+    Fg(Args),
+    Bg(Args),
+    Wait(Args),
+    Complete(Args),
+    /// A call of a function, with the body it had when the command was resolved.
+    Function(Rc<ast::Command>, Args),
 }
 
 impl Display for Command {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Self::Cd(p) => write!(f, "cd: {}", p),
+            Self::Cd(args) => write!(f, "cd: {}", args.arg().unwrap_or("~")),
             _ => write!(f, ""),
         }
     }
 }
 
-impl FromStr for Command {
-    type Err = ShellError;
-    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
-        let (out, err, s) = get_redirection(s);
-
-        let c = handle_input(s);
-        let mut s = c.iter().map(|c| c.as_str());
+/// A running stage of a pipeline.
+enum Stage {
+    Started(libc::pid_t),
+    /// A stage that could not be started.
+    Exited(i32),
+}
 
-        match s.next() {
-            Some("exit") => Err(ShellError::Exit(s.next().unwrap_or("").into())),
-            Some("type") => {
-                let c = s.next();
-                match c {
-                    Some("echo") | Some("type") | Some("exit") | Some("pwd") | Some("cd") => {
-                        Ok(Self::Type(c.expect("must contain valuet").into(), None))
+impl Stage {
+    /// Waits for the stage to finish and returns its exit status. A process killed by a
+    /// signal gets 128 plus the signal number.
+    fn wait(self) -> Result<i32> {
+        match self {
+            Self::Started(pid) => {
+                let mut status = 0;
+                while unsafe { libc::waitpid(pid, &mut status, 0) } == -1 {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err.into());
                     }
-                    Some(c) => match std::env::var("PATH")?
-                        .split(":")
-                        .map(|path| format!("{}/{}", path, c))
-                        .find(|path| std::fs::metadata(path).is_ok())
-                    {
-                        None => Err(ShellError::UnknownType(c.into())),
-                        Some(p) => Ok(Self::Type(c.into(), p.into())),
-                    },
-                    _ => Err(ShellError::UnknownType(c.unwrap_or("").into())),
                 }
+                if libc::WIFSIGNALED(status) {
+                    Ok(128 + libc::WTERMSIG(status))
+                } else {
+                    Ok(libc::WEXITSTATUS(status))
+                }
+            }
+            Self::Exited(status) => Ok(status),
+        }
+    }
+}
+
+/// Runs `run` in a forked copy of the shell as a stage of a pipeline, see [`Command::spawn`].
+/// The copy is a subshell, which has no job control and no jobs of its own.
+fn spawn_forked(
+    shell: &mut Shell,
+    stdin: Option<File>,
+    stdout: Option<File>,
+    pipe_read: Option<&File>,
+    run: impl FnOnce(&mut Shell) -> Result<i32>,
+) -> Result<Stage> {
+    let group = shell.group();
+    match fork()? {
+        0 => {
+            if let Some(group) = group {
+                group.join();
+            }
+            shell.enter_subshell();
+            unsafe {
+                if let Some(stdin) = &stdin {
+                    libc::dup2(stdin.as_raw_fd(), 0);
+                }
+                if let Some(stdout) = &stdout {
+                    libc::dup2(stdout.as_raw_fd(), 1);
+                }
+                if let Some(read) = pipe_read {
+                    libc::close(read.as_raw_fd());
+                }
+            }
+            drop((stdin, stdout));
+            let code = match run(shell) {
+                Ok(code) | Err(ShellError::Exit(code)) => code,
+                Err(e) => {
+                    eprintln!("{e}");
+                    1
+                }
+            };
+            _ = io::stdout().flush();
+            unsafe { libc::_exit(code) }
+        }
+        pid => {
+            shell.started(pid);
+            Ok(Stage::Started(pid))
+        }
+    }
+}
+
+/// Creates an OS pipe, returning its read and write ends.
+fn pipe() -> Result<(File, File)> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error().into());
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+/// Forks the shell, flushing stdout first so that buffered output is not written twice.
+/// Returns the child's pid in the parent and 0 in the child. Like other processes, the child
+/// is killed by `SIGPIPE` when it writes to a pipe nobody reads, which std ignores otherwise.
+fn fork() -> Result<libc::pid_t> {
+    io::stdout().flush()?;
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error().into()),
+        0 => {
+            unsafe { libc::signal(libc::SIGPIPE, libc::SIG_DFL) };
+            Ok(0)
+        }
+        pid => Ok(pid),
+    }
+}
+
+impl List {
+    fn run(&self, shell: &mut Shell) -> Result<i32> {
+        for and_or in &self.items {
+            shell.status = if and_or.background {
+                and_or.start(shell)?
+            } else {
+                and_or.run(shell)?
+            };
+        }
+        Ok(shell.status)
+    }
+}
+
+impl AndOr {
+    fn run(&self, shell: &mut Shell) -> Result<i32> {
+        let mut status = self.first.run(shell)?;
+        for (connector, pipeline) in &self.rest {
+            shell.status = status;
+            match connector {
+                Connector::And if status == 0 => status = pipeline.run(shell)?,
+                Connector::Or if status != 0 => status = pipeline.run(shell)?,
+                _ => {}
             }
-            Some("echo") => Ok(Self::Echo(
-                Args::default()
-                    .with_args(s.map(|arg| arg.to_string()).collect())
-                    .with_stdout(out)
-                    .with_stderr(err),
-            )),
-            Some("pwd") => Ok(Self::Pwd),
-            Some("cd") => Ok(Self::Cd(s.next().unwrap_or("~").trim().into())),
-            Some(c) => {
-                match std::env::var("PATH")?
-                    .split(":")
-                    .map(|path| format!("{}/{}", path, c.trim()))
-                    .find(|path| std::fs::metadata(path).is_ok())
-                {
-                    None => Err(ShellError::NotImplemented(c.into())),
-                    Some(p) => Ok(Self::External(
-                        p,
-                        Args::default()
-                            .with_args(s.map(|arg| arg.to_string()).collect())
-                            .with_stdout(out)
-                            .with_stderr(err),
-                    )),
+        }
+        Ok(status)
+    }
+
+    /// Starts the commands as a job in the background, for `&`. A lone pipeline runs as it
+    /// is, anything longer in a subshell. Without job control the job reads `/dev/null`.
+    fn start(&self, shell: &mut Shell) -> Result<i32> {
+        let stdin = match shell.job_control {
+            true => None,
+            false => Some(File::open("/dev/null")?),
+        };
+        shell.group = shell.job_control.then_some(Group {
+            pgid: 0,
+            foreground: false,
+        });
+        let stages = if self.rest.is_empty() {
+            self.first.start(shell, stdin)
+        } else {
+            spawn_forked(shell, stdin, None, None, |shell| self.run(shell)).map(|s| vec![s])
+        };
+        shell.group = None;
+        let job = Job::new(stages?, self.to_string(), shell.job_control);
+        shell.background(job);
+        Ok(0)
+    }
+}
+
+impl Pipeline {
+    fn run(&self, shell: &mut Shell) -> Result<i32> {
+        let (last, rest) = self.commands.split_last().expect("pipeline has commands");
+        if rest.is_empty() {
+            return last.run(shell);
+        }
+        shell.group = shell.group();
+        let stages = self.start(shell, None);
+        shell.group = None;
+        shell.foreground(Job::new(stages?, self.to_string(), shell.job_control))
+    }
+
+    /// Starts all commands of the pipeline without waiting for them, the first one reading
+    /// `stdin` if given.
+    fn start(&self, shell: &mut Shell, mut stdin: Option<File>) -> Result<Vec<Stage>> {
+        let (last, rest) = self.commands.split_last().expect("pipeline has commands");
+        let mut stages = Vec::new();
+        for stage in rest {
+            let (read, write) = pipe()?;
+            stages.push(stage.spawn(shell, stdin.take(), Some(write), Some(&read))?);
+            stdin = Some(read);
+        }
+        stages.push(last.spawn(shell, stdin, None, None)?);
+        Ok(stages)
+    }
+}
+
+impl ast::Command {
+    fn run(&self, shell: &mut Shell) -> Result<i32> {
+        match self {
+            Self::Simple(cmd) => cmd.run(shell),
+            Self::Group(list) => list.run(shell),
+            Self::If(cmd) => cmd.run(shell),
+            Self::Function(function) => {
+                let body = Rc::clone(&function.body);
+                shell.functions.insert(function.name.clone(), body);
+                Ok(0)
+            }
+        }
+    }
+
+    /// Starts the command as a stage of a pipeline. Compound commands run in a forked copy
+    /// of the shell.
+    fn spawn(
+        &self,
+        shell: &mut Shell,
+        stdin: Option<File>,
+        stdout: Option<File>,
+        pipe_read: Option<&File>,
+    ) -> Result<Stage> {
+        match self {
+            Self::Simple(cmd) => cmd.spawn(shell, stdin, stdout, pipe_read),
+            _ => spawn_forked(shell, stdin, stdout, pipe_read, |shell| self.run(shell)),
+        }
+    }
+}
+
+impl If {
+    fn run(&self, shell: &mut Shell) -> Result<i32> {
+        for (condition, body) in &self.branches {
+            if condition.run(shell)? == 0 {
+                return body.run(shell);
+            }
+        }
+        match &self.otherwise {
+            Some(list) => list.run(shell),
+            None => Ok(0),
+        }
+    }
+}
+
+impl SimpleCommand {
+    /// Expands the words and redirections into the command to run.
+    fn resolve(&self, shell: &mut Shell) -> Result<Command> {
+        shell.last_substitution = None;
+        let mut redirections = Redirections::default();
+        // `>|` overwrites files even with `set -C`, the other operators do not
+        let write = if shell.options.noclobber {
+            Mode::NoClobber
+        } else {
+            Mode::Write
+        };
+        for redirect in &self.redirects {
+            let target = shell.expand_string(&redirect.target)?;
+            let file = |mode| Target::File {
+                path: PathBuf::from(&target),
+                mode,
+            };
+            let (fd, target) = match redirect.op {
+                RedirOp::Out => (1, file(write)),
+                RedirOp::Clobber => (1, file(Mode::Write)),
+                RedirOp::Append => (1, file(Mode::Append)),
+                RedirOp::In => (0, file(Mode::Read)),
+                RedirOp::ReadWrite => (0, file(Mode::ReadWrite)),
+                RedirOp::OutErr => {
+                    redirections.push(1, file(write));
+                    (2, Target::Dup(1))
+                }
+                RedirOp::AppendErr => {
+                    redirections.push(1, file(Mode::Append));
+                    (2, Target::Dup(1))
+                }
+                RedirOp::HereDoc { .. } => (0, Target::Text(target)),
+                RedirOp::HereString => (0, Target::Text(target + "\n")),
+                RedirOp::DupOut | RedirOp::DupIn => {
+                    let fd = if redirect.op == RedirOp::DupOut { 1 } else { 0 };
+                    match target.parse() {
+                        _ if target == "-" => (fd, Target::Close),
+                        Ok(from) => (fd, Target::Dup(from)),
+                        // `>&file` without a descriptor is `&>file`
+                        Err(_) if redirect.op == RedirOp::DupOut && redirect.fd.is_none() => {
+                            redirections.push(1, file(write));
+                            (2, Target::Dup(1))
+                        }
+                        Err(_) => return Err(ShellError::AmbiguousRedirect(target)),
+                    }
                 }
+            };
+            redirections.push(redirect.fd.unwrap_or(fd), target);
+        }
+        let mut env = Vec::new();
+        for assignment in &self.assignments {
+            env.push((
+                assignment.name.clone(),
+                shell.expand_string(&assignment.value)?,
+            ));
+        }
+        let mut args = Vec::new();
+        for word in &self.words {
+            args.extend(shell.expand_word(word)?);
+        }
+        if args.is_empty() && !env.is_empty() {
+            return Ok(Command::Assign(env));
+        }
+        // functions come before builtins and commands of the same name
+        if let Some(body) = args.first().and_then(|name| shell.functions.get(name)) {
+            let args = Args::default()
+                .with_args(args[1..].to_vec())
+                .with_env(env)
+                .with_redirections(redirections);
+            return Ok(Command::Function(Rc::clone(body), args));
+        }
+        Ok(Command::from_args(args, env, redirections))
+    }
+
+    fn run(&self, shell: &mut Shell) -> Result<i32> {
+        match self.resolve(shell) {
+            Ok(cmd) => cmd.run(shell),
+            Err(e @ ShellError::NotSet(..)) if !shell.job_control => {
+                Command::default().report(e, &Fds::new())?;
+                Err(ShellError::Exit(1))
             }
-            None => Ok(Self::NoCommand),
+            Err(e) => Command::default().report(e, &Fds::new()),
+        }
+    }
+
+    /// Starts the command as a stage of a pipeline, see [`Command::spawn`]. A stage that fails
+    /// to start is reported and finishes right away.
+    fn spawn(
+        &self,
+        shell: &mut Shell,
+        stdin: Option<File>,
+        stdout: Option<File>,
+        pipe_read: Option<&File>,
+    ) -> Result<Stage> {
+        match self.resolve(shell) {
+            Ok(cmd) => cmd
+                .spawn(shell, stdin, stdout, pipe_read)
+                .or_else(|e| cmd.report(e, &Fds::new()).map(Stage::Exited)),
+            Err(e) => Command::default().report(e, &Fds::new()).map(Stage::Exited),
         }
     }
 }
 
 impl Command {
-    fn execute(&self) -> Result<()> {
+    fn from_args(
+        args: Vec<String>,
+        env: Vec<(String, String)>,
+        redirections: Redirections,
+    ) -> Self {
+        let mut args = args.into_iter();
+        let Some(name) = args.next() else {
+            return Self::Empty;
+        };
+        let with_args = |args| {
+            Args::default()
+                .with_args(args)
+                .with_redirections(redirections)
+        };
+        let args = with_args(args.collect());
+        match name.as_str() {
+            "exit" => Self::Exit(args),
+            "type" => Self::Type(args),
+            "echo" => Self::Echo(args),
+            "pwd" => Self::Pwd(args),
+            "cd" => Self::Cd(args),
+            "export" => Self::Export(args),
+            "readonly" => Self::Readonly(args),
+            "unset" => Self::Unset(args),
+            "shift" => Self::Shift(args),
+            "set" => Self::Set(args),
+            "jobs" => Self::Jobs(args),
+            "fg" => Self::Fg(args),
+            "bg" => Self::Bg(args),
+            "wait" => Self::Wait(args),
+            "complete" => Self::Complete(args),
+            _ => Self::External(name, args.with_env(env)),
+        }
+    }
+
+    /// Executes the command and reports its failures, which become a non zero status.
+    /// Only `exit` is passed on as an error. The redirections of a builtin are opened before
+    /// it runs and stay open until it is done, and its failures are reported through them.
+    /// External commands open theirs when spawned.
+    fn run(&self, shell: &mut Shell) -> Result<i32> {
+        let fds = match self {
+            Self::Empty | Self::Assign(_) | Self::External(..) => Ok(Fds::new()),
+            Self::Echo(args)
+            | Self::Type(args)
+            | Self::Pwd(args)
+            | Self::Cd(args)
+            | Self::Exit(args)
+            | Self::Export(args)
+            | Self::Readonly(args)
+            | Self::Unset(args)
+            | Self::Shift(args)
+            | Self::Set(args)
+            | Self::Jobs(args)
+            | Self::Fg(args)
+            | Self::Bg(args)
+            | Self::Wait(args)
+            | Self::Complete(args)
+            | Self::Function(_, args) => args.redirections.apply(Fds::new()),
+        };
+        match fds {
+            Ok(fds) => self.execute(shell, &fds).or_else(|e| self.report(e, &fds)),
+            Err(e) => self.report(e, &Fds::new()),
+        }
+    }
+
+    /// Prints the error to the command's standard error and returns its status.
+    fn report(&self, e: ShellError, fds: &Fds) -> Result<i32> {
+        // an error that cannot be written is dropped, the status still tells of it
+        let print = |message: String| _ = Output::new(2, fds).println(&message);
+        match e {
+            ShellError::Exit(_) => Err(e),
+            ShellError::NotImplemented(e) => {
+                print(format!("{e}: command not found"));
+                Ok(127)
+            }
+            ShellError::UnknownType(t) => {
+                print(format!("{t}: not found"));
+                Ok(1)
+            }
+            ShellError::IO(_) => {
+                print(format!("{}: No such file or directory", self));
+                Ok(1)
+            }
+            e @ ShellError::Exec(..) => {
+                print(e.to_string());
+                Ok(126)
+            }
+            e @ (ShellError::InvalidOption(..)
+            | ShellError::Syntax(_)
+            | ShellError::Unmatched(_)
+            | ShellError::UnexpectedEof
+            | ShellError::HereDocEof(_)
+            | ShellError::BadSubstitution(_)) => {
+                print(e.to_string());
+                Ok(2)
+            }
+            e @ (ShellError::BadFd(_)
+            | ShellError::Redirect(..)
+            | ShellError::AmbiguousRedirect(_)
+            | ShellError::Clobber(_)
+            | ShellError::Param(..)
+            | ShellError::NotSet(..)
+            | ShellError::Readonly(_)
+            | ShellError::InvalidName(..)) => {
+                print(e.to_string());
+                Ok(1)
+            }
+            e => {
+                print(format!("{:#?}", e));
+                Ok(1)
+            }
+        }
+    }
+
+    fn execute(&self, shell: &mut Shell, fds: &Fds) -> Result<i32> {
+        let out = Output::new(1, fds);
         match self {
             Self::Echo(args) => {
-                let mut out_str = String::new();
-                for arg in &args.args {
-                    out_str.push_str(arg);
-                    out_str.push(' ');
+                out.println(&args.args.join(" "))?;
+            }
+            Self::Type(args) => match args.arg() {
+                None => {}
+                Some(c) if shell.functions.contains_key(c) => {
+                    out.println(&format!("{c} is a function"))?
                 }
-                args.out.println(&out_str)?;
-                args.err.print("")?;
+                Some(c) if BUILTINS.contains(&c) => {
+                    out.println(&format!("{c} is a shell builtin"))?
+                }
+                Some(c) => match shell.find_in_path(c) {
+                    Some(p) => out.println(&format!("{} is {}", c, p))?,
+                    None => return Err(ShellError::UnknownType(c.into())),
+                },
+            },
+            Self::Empty => return Ok(shell.status),
+            Self::Pwd(_) => out.println(&std::env::current_dir()?.display().to_string())?,
+            Self::Cd(args) => {
+                let old = std::env::current_dir()?;
+                let new = match args.arg() {
+                    Some(dir) => PathBuf::from(dir),
+                    None => expand_tilde("~"),
+                };
+                let new = new.canonicalize()?;
+                std::env::set_current_dir(&new)?;
+                shell.set_var("OLDPWD", old.display().to_string())?;
+                shell.set_var("PWD", new.display().to_string())?;
             }
-            Self::Type(c, p) => match p {
-                None => println!("\r{c} is a shell builtin"),
-                Some(p) => println!("\r{} is {}", c, p),
+            Self::Exit(args) => match args.arg().map(str::parse::<i32>) {
+                None => return Err(ShellError::Exit(shell.status)),
+                Some(Ok(code)) => return Err(ShellError::Exit(code & 0xff)),
+                Some(Err(_)) => {
+                    let code = args.arg().unwrap_or_default();
+                    _ = Output::new(2, fds)
+                        .println(&format!("exit: {code}: numeric argument required"));
+                    return Err(ShellError::Exit(2));
+                }
             },
-            Self::NoCommand => println!(),
-            Self::Pwd => println!("{}", std::env::current_dir()?.display()),
-            Self::Cd(p) => {
-                let p: PathBuf = if p.starts_with("~") {
-                    let home = std::env::home_dir().unwrap_or(Path::new("/").into());
-                    let p = p.trim_start_matches("~").trim_start_matches("/");
-                    home.join(PathBuf::from(p))
-                } else {
-                    PathBuf::from_str(p).unwrap_or_default()
+            Self::External(name, args) => {
+                let stage = self.spawn(shell, None, None, None)?;
+                let command = [name].into_iter().chain(&args.args);
+                let command = command.map(|arg| vars::quote_word(arg));
+                let command = command.collect::<Vec<_>>().join(" ");
+                return shell.foreground(Job::new(vec![stage], command, shell.job_control));
+            }
+            Self::Assign(vars) => {
+                for (name, value) in vars {
+                    shell.set_var(name, value.clone())?;
+                }
+                return Ok(shell.last_substitution.unwrap_or(0));
+            }
+            Self::Export(args) | Self::Readonly(args)
+                if args.args.is_empty() || args.args == ["-p"] =>
+            {
+                let (builtin, export) = match self {
+                    Self::Export(_) => ("export", true),
+                    _ => ("readonly", false),
                 };
-                std::env::set_current_dir(p.canonicalize()?)?;
+                let mut listing = String::new();
+                for (name, var) in shell.vars.sorted() {
+                    if (export && var.exported) || (!export && var.readonly) {
+                        match &var.value {
+                            Some(value) => {
+                                listing += &format!("{builtin} {name}={}\n", quote(value))
+                            }
+                            None => listing += &format!("{builtin} {name}\n"),
+                        }
+                    }
+                }
+                out.print(&listing)?;
             }
-            Self::External(p, args) => {
-                let output = std::process::Command::new(p.split('/').last().unwrap_or(""))
-                    .args(args.args.clone())
-                    .output()?;
-
-                args.out
-                    .print(&format!("{}", String::from_utf8_lossy(&output.stdout)))?;
+            Self::Export(args) | Self::Readonly(args) => {
+                let builtin = match self {
+                    Self::Export(_) => "export",
+                    _ => "readonly",
+                };
+                for arg in &args.args {
+                    let (name, value) = match arg.split_once('=') {
+                        Some((name, value)) => (name, Some(value.into())),
+                        None => (arg.as_str(), None),
+                    };
+                    if !vars::is_name(name) {
+                        return Err(ShellError::InvalidName(builtin, arg.clone()));
+                    }
+                    match self {
+                        Self::Export(_) => shell.vars.export(name, value)?,
+                        _ => shell.vars.readonly(name, value)?,
+                    }
+                }
+            }
+            Self::Unset(args) => {
+                for name in args.args.iter().filter(|name| *name != "-v") {
+                    if !vars::is_name(name) {
+                        return Err(ShellError::InvalidName("unset", name.clone()));
+                    }
+                    shell.vars.unset(name)?;
+                }
+            }
+            Self::Set(args) if !args.args.is_empty() => {
+                let mut words = args.args.iter();
+                while let Some(arg) = words.next() {
+                    let on = arg.starts_with('-');
+                    match arg.as_str() {
+                        "-o" | "+o" => match words.next() {
+                            Some(name) => shell.options.set(name, on)?,
+                            None => out.print(&shell.options.listing(!on))?,
+                        },
+                        // the remaining words become the positional parameters
+                        "-" | "--" => {
+                            shell.positional = words.cloned().collect();
+                            break;
+                        }
+                        _ if arg.len() > 1 && (on || arg.starts_with('+')) => {
+                            for flag in arg[1..].chars() {
+                                shell.options.set_flag(flag, on)?;
+                            }
+                        }
+                        "+" => return Err(ShellError::InvalidOption("set", arg.clone())),
+                        _ => {
+                            shell.positional = [arg].into_iter().chain(words).cloned().collect();
+                            break;
+                        }
+                    }
+                }
+            }
+            Self::Shift(args) => {
+                let n = match args.arg() {
+                    None => 1,
+                    Some(n) => n.parse().map_err(|_| {
+                        ShellError::Param("shift".into(), format!("{n}: numeric argument required"))
+                    })?,
+                };
+                if n > shell.positional.len() {
+                    return Err(ShellError::Param(
+                        "shift".into(),
+                        "shift count out of range".into(),
+                    ));
+                }
+                shell.positional.drain(..n);
+            }
+            Self::Jobs(args) => {
+                shell.notify()?;
+                let mut pids = false;
+                let mut only_pids = false;
+                for arg in &args.args {
+                    match arg.as_str() {
+                        "-l" => pids = true,
+                        "-p" => only_pids = true,
+                        _ => return Err(ShellError::InvalidOption("jobs", arg.clone())),
+                    }
+                }
+                out.print(&shell.jobs.listing(pids, only_pids))?;
+            }
+            Self::Fg(args) => return shell.fg(args.arg(), &out),
+            Self::Bg(args) => shell.bg(args.arg(), &out)?,
+            Self::Wait(args) => return shell.wait(&args.args),
+            Self::Complete(args) => out.print(&shell.complete(&args.args)?)?,
+            Self::Function(body, args) => return shell.call(body, args, fds),
+            Self::Set(_) => {
+                let mut listing = String::new();
+                for (name, var) in shell.vars.sorted() {
+                    if let Some(value) = &var.value {
+                        listing += &format!("{name}={}\n", quote(value));
+                    }
+                }
+                out.print(&listing)?;
+            }
+        }
+        Ok(0)
+    }
 
-                args.err
-                    .print(&format!("{}", String::from_utf8_lossy(&output.stderr)))?;
+    /// Starts this command as a process. External commands are spawned with their ends of the
+    /// pipes, builtins run in a forked copy of the shell.
+    /// `pipe_read` is the read end of the pipe behind `stdout`, which the stage must not keep
+    /// open. Streams that are neither piped nor redirected are inherited from the shell.
+    fn spawn(
+        &self,
+        shell: &mut Shell,
+        stdin: Option<File>,
+        stdout: Option<File>,
+        pipe_read: Option<&File>,
+    ) -> Result<Stage> {
+        match self {
+            Self::External(name, args) => {
+                let Some(path) = shell.find_in_path(name) else {
+                    let fds = args.redirections.apply(Fds::new()).unwrap_or_default();
+                    return self
+                        .report(ShellError::NotImplemented(name.into()), &fds)
+                        .map(Stage::Exited);
+                };
+                let mut cmd = process::Command::new(path);
+                cmd.arg0(name)
+                    .args(&args.args)
+                    .env_clear()
+                    .envs(shell.vars.exported())
+                    .envs(args.env.iter().map(|(name, value)| (name, value)));
+                let fds = args.redirections.apply(Fds::from_iter(
+                    [(0, stdin), (1, stdout)]
+                        .into_iter()
+                        .filter_map(|(fd, file)| Some((fd, Some(file?)))),
+                ))?;
+                // the error of a program that cannot be started goes where its own would
+                let stderr: Fds = fds
+                    .get(&2)
+                    .map(|file| (2, file.as_ref().and_then(|f| f.try_clone().ok())))
+                    .into_iter()
+                    .collect();
+                let group = shell.group();
+                unsafe {
+                    cmd.pre_exec(move || {
+                        if let Some(group) = group {
+                            group.join();
+                        }
+                        signals::reset();
+                        redirect::install(&fds)
+                    })
+                };
+                let pid = match cmd.spawn() {
+                    Ok(child) => child.id() as libc::pid_t,
+                    Err(e) => {
+                        return self
+                            .report(ShellError::Exec(name.into(), e), &stderr)
+                            .map(Stage::Exited)
+                    }
+                };
+                shell.started(pid);
+                Ok(Stage::Started(pid))
             }
+            _ => spawn_forked(shell, stdin, stdout, pipe_read, |shell| self.run(shell)),
         }
-        Ok(())
     }
 }
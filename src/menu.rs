@@ -0,0 +1,183 @@
+//! The menu of completions shown under the line when a word has several, in a grid that fits
+//! the terminal with what kind of completion each is next to it. Tab and Shift-Tab go through
+//! the completions and the arrows move across the grid, with the line showing the one
+//! selected. Enter takes it, Esc and Ctrl-G go back to what was typed, and any other key
+//! takes it and goes on as usual.
+
+use std::ops::Range;
+
+use termion::event::Key;
+
+use crate::{
+    complete::Completion,
+    editor::{self, Editor},
+};
+
+/// Lists longer than this are only shown after asking.
+pub const QUERY_ITEMS: usize = 100;
+
+pub enum Selection {
+    Moved,
+    Accept {
+        /// Whether the key goes on to the editor.
+        pass: bool,
+    },
+    Cancel {
+        pass: bool,
+    },
+}
+
+pub struct Menu {
+    completions: Vec<Completion>,
+    /// The line before the word the completions replace.
+    before: String,
+    /// What was typed of the word.
+    typed: String,
+    /// The line after the word.
+    after: String,
+    selected: Option<usize>,
+    /// The rows of the grid, which the completions go down first.
+    rows: usize,
+}
+
+impl Menu {
+    pub fn new(completions: Vec<Completion>, range: Range<usize>, editor: &Editor) -> Self {
+        let line = editor.line();
+        let (columns, _) = editor::size();
+        Self {
+            rows: Layout::new(&completions, columns).rows,
+            completions,
+            before: line[..range.start].into(),
+            typed: line[range.clone()].into(),
+            after: line[range.end..].into(),
+            selected: None,
+        }
+    }
+
+    pub fn key(&mut self, key: &Key) -> Selection {
+        let n = self.completions.len();
+        let rows = self.rows;
+        self.selected = Some(match (key, self.selected) {
+            (Key::Char('\t') | Key::Down | Key::Left | Key::Right, None) => 0,
+            (Key::BackTab | Key::Up, None) => n - 1,
+            (Key::Char('\t') | Key::Down, Some(i)) => (i + 1) % n,
+            (Key::BackTab | Key::Up, Some(i)) => (i + n - 1) % n,
+            // across a row, and on to the start of the next one
+            (Key::Right, Some(i)) if i + rows < n => i + rows,
+            (Key::Right, Some(i)) => (i % rows + 1) % rows,
+            (Key::Left, Some(i)) if i >= rows => i - rows,
+            (Key::Left, Some(i)) => {
+                let row = (i + rows - 1) % rows;
+                (n - 1 - row) / rows * rows + row
+            }
+            // without a selection, Enter runs the line
+            (Key::Char('\n'), selected) => {
+                return Selection::Accept {
+                    pass: selected.is_none(),
+                }
+            }
+            (Key::Esc | Key::Ctrl('g'), _) => return Selection::Cancel { pass: false },
+            (Key::Ctrl('c'), _) => return Selection::Cancel { pass: true },
+            _ => return Selection::Accept { pass: true },
+        });
+        Selection::Moved
+    }
+
+    /// Shows the grid under the line, and the completion selected in the line.
+    pub fn show(&self, editor: &mut Editor) {
+        let text = match self.selected {
+            Some(i) => &self.completions[i].text,
+            None => &self.typed,
+        };
+        editor.set_line(format!("{}{text}{}", self.before, self.after));
+        editor.set_cursor(self.before.len() + text.len());
+        let (columns, _) = editor::size();
+        editor.set_below(grid(&self.completions, columns, self.selected));
+    }
+
+    /// Keeps the completion selected in the line.
+    pub fn accept(self, editor: &mut Editor) {
+        editor.set_below(String::new());
+    }
+
+    /// Puts back what was typed.
+    pub fn cancel(mut self, editor: &mut Editor) {
+        self.selected = None;
+        self.show(editor);
+        editor.set_below(String::new());
+    }
+}
+
+/// The sizes of a grid of completions.
+struct Layout {
+    /// The widths of the names and of the descriptions.
+    names: usize,
+    descriptions: usize,
+    columns: usize,
+    rows: usize,
+}
+
+impl Layout {
+    fn new(completions: &[Completion], width: usize) -> Self {
+        let names = completions
+            .iter()
+            .map(|completion| completion.display.chars().count())
+            .max()
+            .unwrap_or(0);
+        let descriptions = completions
+            .iter()
+            .filter_map(|completion| completion.description)
+            .map(str::len)
+            .max()
+            .unwrap_or(0);
+        let cell = match descriptions {
+            0 => names,
+            _ => names + 2 + descriptions,
+        };
+        // no row fills the width, which the terminal would only wrap at the next character
+        let columns = ((width.saturating_sub(1) + 2) / (cell + 2)).max(1);
+        let rows = completions.len().div_ceil(columns).max(1);
+        Self {
+            names,
+            descriptions,
+            columns,
+            rows,
+        }
+    }
+}
+
+/// Whether the grid of completions fits on the screen under the line.
+pub fn fits(completions: &[Completion]) -> bool {
+    let (columns, rows) = editor::size();
+    Layout::new(completions, columns).rows + 2 <= rows
+}
+
+/// Lays the completions out in columns that fit in `width`, going down first, with the
+/// selected one in reverse video.
+pub fn grid(completions: &[Completion], width: usize, selected: Option<usize>) -> String {
+    let layout = Layout::new(completions, width);
+    let mut lines = Vec::new();
+    for row in 0..layout.rows {
+        let mut line = String::new();
+        for column in 0..layout.columns {
+            let i = column * layout.rows + row;
+            let Some(completion) = completions.get(i) else {
+                break;
+            };
+            if column > 0 {
+                line += "  ";
+            }
+            let mut cell = format!("{:<1$}", completion.display, layout.names);
+            if layout.descriptions > 0 {
+                let description = completion.description.unwrap_or_default();
+                cell += &format!("  \x1b[2m{description:<0$}\x1b[22m", layout.descriptions);
+            }
+            match selected == Some(i) {
+                true => line += &format!("\x1b[7m{cell}\x1b[27m"),
+                false => line += &cell,
+            }
+        }
+        lines.push(line.trim_end().to_string());
+    }
+    lines.join("\n")
+}
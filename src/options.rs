@@ -0,0 +1,100 @@
+//! Shell options, turned on with `set -o name` or `set -x` for the ones that have a letter,
+//! and off again with `+` instead of `-`.
+
+use crate::{Result, ShellError};
+
+#[derive(Debug, Default)]
+pub struct Options {
+    /// `-C`: `>` does not overwrite existing files.
+    pub noclobber: bool,
+    /// `-f`: no pathname expansion.
+    pub noglob: bool,
+    /// `**` in a pattern matches any number of directories.
+    pub globstar: bool,
+    /// `-H`: `!` expands to commands of the history, which an interactive shell turns on.
+    pub histexpand: bool,
+    /// The line editor takes the keys of emacs, which an interactive shell turns on.
+    pub emacs: bool,
+    /// The line editor takes the keys of vi.
+    pub vi: bool,
+}
+
+impl Options {
+    /// The long names of the options with their single letter flags.
+    const NAMES: [(&'static str, Option<char>); 6] = [
+        ("emacs", None),
+        ("globstar", None),
+        ("histexpand", Some('H')),
+        ("noclobber", Some('C')),
+        ("noglob", Some('f')),
+        ("vi", None),
+    ];
+
+    fn option(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            "emacs" => Some(&mut self.emacs),
+            "globstar" => Some(&mut self.globstar),
+            "histexpand" => Some(&mut self.histexpand),
+            "noclobber" => Some(&mut self.noclobber),
+            "noglob" => Some(&mut self.noglob),
+            "vi" => Some(&mut self.vi),
+            _ => None,
+        }
+    }
+
+    pub fn set(&mut self, name: &str, on: bool) -> Result<()> {
+        let option = self
+            .option(name)
+            .ok_or_else(|| ShellError::InvalidOption("set", name.into()))?;
+        *option = on;
+        // the editor has one keymap at a time
+        match name {
+            "emacs" if on => self.vi = false,
+            "vi" if on => self.emacs = false,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    pub fn set_flag(&mut self, flag: char, on: bool) -> Result<()> {
+        match Self::NAMES.iter().find(|(_, f)| *f == Some(flag)) {
+            Some((name, _)) => self.set(name, on),
+            None => Err(ShellError::InvalidOption("set", format!("-{flag}"))),
+        }
+    }
+
+    fn get(&self, name: &str) -> bool {
+        match name {
+            "emacs" => self.emacs,
+            "globstar" => self.globstar,
+            "histexpand" => self.histexpand,
+            "noclobber" => self.noclobber,
+            "noglob" => self.noglob,
+            "vi" => self.vi,
+            _ => false,
+        }
+    }
+
+    /// The letters of the options that are on, `$-`.
+    pub fn flags(&self) -> String {
+        Self::NAMES
+            .iter()
+            .filter(|(name, _)| self.get(name))
+            .filter_map(|(_, flag)| *flag)
+            .collect()
+    }
+
+    /// Lists the options as `set -o` does, or as commands that restore them for `set +o`.
+    pub fn listing(&self, as_commands: bool) -> String {
+        let mut listing = String::new();
+        for (name, _) in Self::NAMES {
+            let on = self.get(name);
+            listing += &if as_commands {
+                format!("set {}o {name}\n", if on { '-' } else { '+' })
+            } else {
+                format!("{name:<15}\t{}\n", if on { "on" } else { "off" })
+            };
+        }
+        listing
+    }
+}
@@ -0,0 +1,303 @@
+use std::{iter::Peekable, rc::Rc, vec::IntoIter};
+
+use crate::{
+    ast::{
+        AndOr, Assignment, Command, Connector, Function, If, List, Pipeline, Redirect,
+        SimpleCommand,
+    },
+    lexer::{Lexer, Op, Token},
+    vars::is_name,
+    Result, ShellError,
+};
+
+/// Words that are only special at the start of a command, and only if they are unquoted.
+const RESERVED: [&str; 7] = ["{", "}", "if", "then", "elif", "else", "fi"];
+
+/// Parses a command line into its syntax tree. Input that ends before a command is complete
+/// gives [`ShellError::UnexpectedEof`], or an unmatched quote or here-document error.
+pub fn parse(input: &str) -> Result<List> {
+    Parser {
+        tokens: Lexer::new(input).tokenize()?.into_iter().peekable(),
+    }
+    .list(&[])
+}
+
+struct Parser {
+    tokens: Peekable<IntoIter<Token>>,
+}
+
+impl Parser {
+    /// Parses commands up to one of the reserved words in `ends`, which is left for the
+    /// caller. An empty `ends` parses all of the input.
+    fn list(&mut self, ends: &[&str]) -> Result<List> {
+        let mut list = List::default();
+        loop {
+            self.skip_newlines();
+            match self.tokens.peek() {
+                None if ends.is_empty() => return Ok(list),
+                None => return Err(ShellError::UnexpectedEof),
+                Some(t) if reserved(t).is_some_and(|w| ends.contains(&w)) => {
+                    if list.items.is_empty() {
+                        return Err(ShellError::Syntax(t.to_string()));
+                    }
+                    return Ok(list);
+                }
+                _ => {}
+            }
+            let mut and_or = self.and_or()?;
+            match self.tokens.peek() {
+                None => {}
+                Some(Token::Op(Op::Semi) | Token::Newline) => _ = self.tokens.next(),
+                Some(Token::Op(Op::Background)) => {
+                    self.tokens.next();
+                    and_or.background = true;
+                }
+                Some(t) if reserved(t).is_some_and(|w| ends.contains(&w)) => {}
+                Some(t) => return Err(ShellError::Syntax(t.to_string())),
+            }
+            list.items.push(and_or);
+        }
+    }
+
+    /// Consumes the reserved word `word`, which has to come next.
+    fn expect(&mut self, word: &str) -> Result<()> {
+        match self.tokens.next() {
+            Some(t) if reserved(&t) == Some(word) => Ok(()),
+            Some(t) => Err(ShellError::Syntax(t.to_string())),
+            None => Err(ShellError::UnexpectedEof),
+        }
+    }
+
+    fn and_or(&mut self) -> Result<AndOr> {
+        let first = self.pipeline()?;
+        let mut rest = Vec::new();
+        loop {
+            let connector = match self.tokens.peek() {
+                Some(Token::Op(Op::And)) => Connector::And,
+                Some(Token::Op(Op::Or)) => Connector::Or,
+                _ => {
+                    return Ok(AndOr {
+                        first,
+                        rest,
+                        background: false,
+                    })
+                }
+            };
+            self.tokens.next();
+            self.skip_newlines();
+            rest.push((connector, self.pipeline()?));
+        }
+    }
+
+    fn pipeline(&mut self) -> Result<Pipeline> {
+        let mut commands = vec![self.command()?];
+        while self.tokens.next_if_eq(&Token::Op(Op::Pipe)).is_some() {
+            self.skip_newlines();
+            commands.push(self.command()?);
+        }
+        Ok(Pipeline { commands })
+    }
+
+    fn command(&mut self) -> Result<Command> {
+        match self.tokens.peek().and_then(reserved) {
+            Some("{") => {
+                self.tokens.next();
+                let list = self.list(&["}"])?;
+                self.expect("}")?;
+                Ok(Command::Group(list))
+            }
+            Some("if") => {
+                self.tokens.next();
+                let mut branches = Vec::new();
+                loop {
+                    let condition = self.list(&["then"])?;
+                    self.expect("then")?;
+                    let body = self.list(&["elif", "else", "fi"])?;
+                    branches.push((condition, body));
+                    match self.tokens.next() {
+                        Some(t) if reserved(&t) == Some("elif") => {}
+                        Some(t) if reserved(&t) == Some("else") => {
+                            let otherwise = self.list(&["fi"])?;
+                            self.expect("fi")?;
+                            return Ok(Command::If(If {
+                                branches,
+                                otherwise: Some(otherwise),
+                            }));
+                        }
+                        // the list only ends at one of the three
+                        _ => {
+                            return Ok(Command::If(If {
+                                branches,
+                                otherwise: None,
+                            }))
+                        }
+                    }
+                }
+            }
+            Some(word) => Err(ShellError::Syntax(word.into())),
+            _ => {
+                let cmd = self.simple_command()?;
+                match self.tokens.peek() {
+                    Some(Token::Op(Op::LParen)) => self.function(cmd),
+                    _ => Ok(Command::Simple(cmd)),
+                }
+            }
+        }
+    }
+
+    /// Parses the rest of a function definition, `() compound-command`, whose name was read
+    /// as the simple command `cmd`.
+    fn function(&mut self, cmd: SimpleCommand) -> Result<Command> {
+        let name = match (&cmd.assignments[..], &cmd.words[..], &cmd.redirects[..]) {
+            ([], [word], []) => word.as_unquoted().filter(|name| is_name(name)),
+            _ => None,
+        };
+        let Some(name) = name.map(String::from) else {
+            return Err(ShellError::Syntax(Op::LParen.to_string()));
+        };
+        self.tokens.next();
+        match self.tokens.next() {
+            Some(Token::Op(Op::RParen)) => {}
+            Some(t) => return Err(ShellError::Syntax(t.to_string())),
+            None => return Err(ShellError::UnexpectedEof),
+        }
+        self.skip_newlines();
+        match self.tokens.peek() {
+            Some(t) if matches!(reserved(t), Some("{" | "if")) => {}
+            Some(t) => return Err(ShellError::Syntax(t.to_string())),
+            None => return Err(ShellError::UnexpectedEof),
+        }
+        Ok(Command::Function(Function {
+            name,
+            body: Rc::new(self.command()?),
+        }))
+    }
+
+    fn simple_command(&mut self) -> Result<SimpleCommand> {
+        let mut cmd = SimpleCommand::default();
+        loop {
+            match self.tokens.peek() {
+                Some(Token::Word(_)) => {
+                    let Some(Token::Word(word)) = self.tokens.next() else {
+                        unreachable!()
+                    };
+                    match word.as_assignment() {
+                        Some((name, value)) if cmd.words.is_empty() => {
+                            cmd.assignments.push(Assignment { name, value })
+                        }
+                        _ => cmd.words.push(word),
+                    }
+                }
+                Some(Token::Redirect(..)) => {
+                    let Some(Token::Redirect(fd, op)) = self.tokens.next() else {
+                        unreachable!()
+                    };
+                    let target = match self.tokens.next() {
+                        Some(Token::Word(word)) => word,
+                        Some(t) => return Err(ShellError::Syntax(t.to_string())),
+                        None => return Err(ShellError::Syntax(Token::Newline.to_string())),
+                    };
+                    cmd.redirects.push(Redirect { fd, op, target });
+                }
+                _ => break,
+            }
+        }
+        if cmd.assignments.is_empty() && cmd.words.is_empty() && cmd.redirects.is_empty() {
+            return match self.tokens.next() {
+                Some(t) => Err(ShellError::Syntax(t.to_string())),
+                None => Err(ShellError::UnexpectedEof),
+            };
+        }
+        Ok(cmd)
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.tokens.next_if_eq(&Token::Newline).is_some() {}
+    }
+}
+
+/// The reserved word a token is, if it is one.
+fn reserved(token: &Token) -> Option<&'static str> {
+    let Token::Word(word) = token else {
+        return None;
+    };
+    let word = word.as_unquoted()?;
+    RESERVED.into_iter().find(|r| *r == word)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_lists_and_pipelines() {
+        let list = parse("a | b && c || d; e &\nf").unwrap();
+        assert_eq!(list.to_string(), "a | b && c || d; e & f");
+    }
+
+    #[test]
+    fn parses_compound_commands() {
+        let list = parse("if a; then b; elif c\nthen d; else { e; }; fi").unwrap();
+        assert_eq!(
+            list.to_string(),
+            "if a; then b; elif c; then d; else { e; }; fi"
+        );
+    }
+
+    #[test]
+    fn parses_function_definitions() {
+        let list = parse("f() { a; }; g ()\nif a; then b; fi").unwrap();
+        assert_eq!(list.to_string(), "f() { a; }; g() if a; then b; fi");
+        for (input, token) in [("f() a", "a"), ("a b() { c; }", "("), ("'f'() { a; }", "(")] {
+            match parse(input) {
+                Err(ShellError::Syntax(t)) => assert_eq!(t, token, "{input:?}"),
+                res => panic!("{input:?}: {res:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn incomplete_input() {
+        for input in [
+            "a |",
+            "a &&",
+            "a ||",
+            "if a; then b",
+            "{ a",
+            "f()",
+            "f() { a",
+            "echo 'a",
+            "echo \"a",
+            "echo $(a",
+            "echo a\\",
+            "cat <<EOF\nbody",
+        ] {
+            let res = parse(input);
+            assert!(res.is_err_and(|e| e.is_incomplete()), "{input:?}");
+        }
+    }
+
+    #[test]
+    fn syntax_errors() {
+        for (input, token) in [
+            ("| a", "|"),
+            ("a && && b", "&&"),
+            ("; a", ";"),
+            ("a; fi", "fi"),
+            ("if then a; fi", "then"),
+            ("{ }", "}"),
+        ] {
+            match parse(input) {
+                Err(ShellError::Syntax(t)) => assert_eq!(t, token, "{input:?}"),
+                res => panic!("{input:?}: {res:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn reserved_words_only_start_commands() {
+        let list = parse("echo if then fi").unwrap();
+        assert_eq!(list.to_string(), "echo if then fi");
+        assert!(parse("'if' a").is_ok());
+    }
+}
@@ -0,0 +1,162 @@
+//! Shell pattern matching with `*`, `?` and `[...]`. A backslash makes the next character
+//! match literally, which is how quoted parts of a pattern are passed in.
+
+/// Returns whether `pattern` matches all of `text`.
+pub fn matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    matches_at(&pattern, &text)
+}
+
+fn matches_at(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // where to resume after the last `*`: its pattern position and the text it swallowed
+    let mut backtrack = None;
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            }
+            Some('?') => Some(1),
+            Some('[') => match bracket(&pattern[p..], text[t]) {
+                Some((true, len)) => Some(len),
+                Some((false, _)) => None,
+                // an unclosed `[` is an ordinary character
+                None => (text[t] == '[').then_some(1),
+            },
+            Some('\\') if p + 1 < pattern.len() => (pattern[p + 1] == text[t]).then_some(2),
+            Some(&c) => (c == text[t]).then_some(1),
+            None => None,
+        };
+        match (step, backtrack) {
+            (Some(len), _) => {
+                p += len;
+                t += 1;
+            }
+            (None, Some((star, swallowed))) => {
+                p = star + 1;
+                t = swallowed + 1;
+                backtrack = Some((star, swallowed + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Matches `c` against the bracket expression at the start of `pattern`, returning whether
+/// it matched and the length of the expression, or `None` if it is not closed.
+fn bracket(pattern: &[char], c: char) -> Option<(bool, usize)> {
+    let mut i = 1;
+    let negate = matches!(pattern.get(i), Some('!' | '^'));
+    if negate {
+        i += 1;
+    }
+    let mut matched = false;
+    let mut first = true;
+    loop {
+        let mut start = *pattern.get(i)?;
+        if start == ']' && !first {
+            return Some((matched != negate, i + 1));
+        }
+        first = false;
+        if start == '\\' {
+            i += 1;
+            start = *pattern.get(i)?;
+        }
+        i += 1;
+        if pattern.get(i) == Some(&'-') && pattern.get(i + 1).is_some_and(|c| *c != ']') {
+            let mut end = pattern[i + 1];
+            i += 2;
+            if end == '\\' {
+                end = *pattern.get(i)?;
+                i += 1;
+            }
+            matched |= (start..=end).contains(&c);
+        } else {
+            matched |= start == c;
+        }
+    }
+}
+
+/// Returns whether `pattern` has any unescaped `*`, `?` or `[`, i.e. can match more than one
+/// text.
+pub fn has_meta(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' | '?' | '[' => return true,
+            '\\' => _ = chars.next(),
+            _ => {}
+        }
+    }
+    false
+}
+
+/// Escapes the characters that are special in a pattern.
+pub fn escape(text: &str) -> String {
+    let mut res = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            res.push('\\');
+        }
+        res.push(c);
+    }
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn star_and_question_mark() {
+        assert!(matches("*", ""));
+        assert!(matches("a*c", "abbc"));
+        assert!(matches("a*b*c", "aXbYbc"));
+        assert!(!matches("a*c", "abcd"));
+        assert!(matches("a?c", "abc"));
+        assert!(!matches("a?c", "ac"));
+        assert!(matches("??", "äö"));
+    }
+
+    #[test]
+    fn brackets() {
+        assert!(matches("[abc]", "b"));
+        assert!(!matches("[abc]", "d"));
+        assert!(matches("[a-c]x", "cx"));
+        assert!(!matches("[a-c]", "-"));
+        assert!(matches("[!a-c]", "d"));
+        assert!(matches("[^a-c]", "d"));
+        assert!(!matches("[!a-c]", "b"));
+        // a `]` or `-` at the edges is a member
+        assert!(matches("[]a]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("[\\]]", "]"));
+    }
+
+    #[test]
+    fn unclosed_bracket_is_literal() {
+        assert!(matches("[a", "[a"));
+        assert!(!matches("[a", "a"));
+    }
+
+    #[test]
+    fn escaped_characters_are_literal() {
+        assert!(matches("\\*", "*"));
+        assert!(!matches("\\*", "a"));
+        assert!(matches("a\\?", "a?"));
+        assert!(matches(&escape("[x]*"), "[x]*"));
+        assert!(!matches(&escape("a*"), "ab"));
+    }
+
+    #[test]
+    fn meta_characters() {
+        assert!(has_meta("a*"));
+        assert!(has_meta("[a]"));
+        assert!(!has_meta("a\\*"));
+        assert!(!has_meta(&escape("a?")));
+    }
+}
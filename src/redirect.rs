@@ -0,0 +1,144 @@
+//! Redirections as a table of what the file descriptors of a command refer to. The table is
+//! applied in order, so `> log 2>&1` sends both streams to `log` while `2>&1 > log` only
+//! sends stdout there.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    os::fd::{AsRawFd, FromRawFd, RawFd},
+    path::{Path, PathBuf},
+};
+
+use crate::{pipe, Result, ShellError};
+
+/// The files that descriptors refer to after applying redirections. `None` is a closed
+/// descriptor, and descriptors that are not in the table are inherited from the shell.
+pub type Fds = BTreeMap<u32, Option<File>>;
+
+#[derive(Debug)]
+pub enum Target {
+    File {
+        path: PathBuf,
+        mode: Mode,
+    },
+    /// A copy of another descriptor, `>&n` or `<&n`.
+    Dup(u32),
+    /// `>&-` or `<&-`
+    Close,
+    /// The text of a here-document or here-string.
+    Text(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Read,
+    Write,
+    /// Like `Write`, but refuses to truncate an existing regular file, for `set -C`.
+    NoClobber,
+    Append,
+    ReadWrite,
+}
+
+impl Mode {
+    fn open(self, path: &Path) -> io::Result<File> {
+        let mut options = OpenOptions::new();
+        match self {
+            Self::Read => options.read(true),
+            Self::Write => options.write(true).create(true).truncate(true),
+            Self::NoClobber => {
+                return match OpenOptions::new().write(true).create_new(true).open(path) {
+                    // devices like /dev/null can still be written to
+                    Err(e) if fs::metadata(path).is_ok_and(|m| !m.is_file()) => {
+                        OpenOptions::new().write(true).open(path).map_err(|_| e)
+                    }
+                    res => res,
+                };
+            }
+            Self::Append => options.append(true).create(true),
+            Self::ReadWrite => options.read(true).write(true).create(true),
+        };
+        options.open(path)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Redirections {
+    targets: Vec<(u32, Target)>,
+}
+
+impl Redirections {
+    pub fn push(&mut self, fd: u32, target: Target) {
+        self.targets.push((fd, target));
+    }
+
+    /// Opens the targets in order on top of `fds`, which holds the descriptors the command
+    /// starts out with, like the ends of its pipes. The result is ready for [`install`].
+    pub fn apply(&self, mut fds: Fds) -> Result<Fds> {
+        for (fd, target) in &self.targets {
+            let file = match target {
+                Target::File { path, mode } => Some(mode.open(path).map_err(|e| {
+                    let path = path.to_string_lossy().into_owned();
+                    match e.kind() {
+                        io::ErrorKind::AlreadyExists => ShellError::Clobber(path),
+                        _ => ShellError::Redirect(path, e),
+                    }
+                })?),
+                Target::Dup(from) => match fds.get(from) {
+                    Some(Some(file)) => Some(file.try_clone()?),
+                    Some(None) => return Err(ShellError::BadFd(*from)),
+                    None => Some(dup(*from as RawFd, 0).map_err(|_| ShellError::BadFd(*from))?),
+                },
+                Target::Close => None,
+                Target::Text(text) => {
+                    // a thread feeds the text, so that it may be larger than the pipe's buffer
+                    let (read, mut write) = pipe()?;
+                    let text = text.clone();
+                    std::thread::spawn(move || write.write_all(text.as_bytes()));
+                    Some(read)
+                }
+            };
+            fds.insert(*fd, file);
+        }
+        // moves the files above all the descriptors being set, so that installing one
+        // cannot close the file another one is copied from
+        let above = fds.keys().last().map_or(0, |fd| *fd as RawFd + 1).max(10);
+        for file in fds.values_mut().flatten() {
+            if file.as_raw_fd() < above {
+                *file = dup(file.as_raw_fd(), above)?;
+            }
+        }
+        Ok(fds)
+    }
+}
+
+/// Duplicates a descriptor to a close-on-exec one that is at least `min`.
+fn dup(fd: RawFd, min: RawFd) -> io::Result<File> {
+    match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, min) } {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(unsafe { File::from_raw_fd(fd) }),
+    }
+}
+
+/// Copies the descriptors of the current process that `fds` sets, so that installing the
+/// copies puts them back. A descriptor that is closed stays closed.
+pub fn save(fds: &Fds) -> Fds {
+    fds.keys()
+        .map(|fd| (*fd, dup(*fd as RawFd, 10).ok()))
+        .collect()
+}
+
+/// Makes the descriptors of the current process what `fds` says. It does not allocate, so
+/// it is safe to call between fork and exec.
+pub fn install(fds: &Fds) -> io::Result<()> {
+    for (fd, file) in fds {
+        let res = match file {
+            Some(file) => unsafe { libc::dup2(file.as_raw_fd(), *fd as RawFd) },
+            None => unsafe { libc::close(*fd as RawFd) },
+        };
+        if res == -1 && file.is_some() {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
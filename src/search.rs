@@ -0,0 +1,160 @@
+//! Incremental search through the history, Ctrl-R for older commands and Ctrl-S for newer
+//! ones. The editor shows the match with a prompt like bash's `(reverse-i-search)`query': `.
+
+use termion::event::Key;
+
+use crate::editor::Editor;
+
+/// What a key did to a search.
+pub enum Step {
+    /// The key was part of the search.
+    Searching,
+    /// The search ends with the line it found. The key is handled as usual, unless `pass`
+    /// is false.
+    Accept { pass: bool },
+    /// The search ends with the line it started with, for Ctrl-G and Ctrl-C. Ctrl-C still
+    /// cancels the line as usual.
+    Cancel { pass: bool },
+}
+
+#[derive(Debug)]
+pub struct Search {
+    query: String,
+    reverse: bool,
+    /// The entry and the byte offset in it of the match that is shown.
+    found: Option<(usize, usize)>,
+    failed: bool,
+    /// What the editor showed before the search.
+    prompt: String,
+    line: String,
+    cursor: usize,
+}
+
+impl Search {
+    pub fn new(editor: &Editor, reverse: bool) -> Self {
+        Self {
+            query: String::new(),
+            reverse,
+            found: None,
+            failed: false,
+            prompt: editor.prompt().into(),
+            line: editor.line().into(),
+            cursor: editor.cursor(),
+        }
+    }
+
+    pub fn key(&mut self, key: &Key, entries: &[String]) -> Step {
+        match key {
+            Key::Ctrl('r') | Key::Ctrl('s') => {
+                self.reverse = *key == Key::Ctrl('r');
+                // the next match after the one shown
+                let from = match self.found {
+                    Some((entry, offset)) if self.reverse => match offset.checked_sub(1) {
+                        Some(offset) => Some((entry, offset)),
+                        None => entry.checked_sub(1).map(|entry| (entry, usize::MAX)),
+                    },
+                    Some((entry, offset)) => Some((entry, offset + 1)),
+                    None => self.start(entries),
+                };
+                self.find(from, entries);
+            }
+            Key::Backspace => {
+                self.query.pop();
+                self.found = None;
+                self.find(self.start(entries), entries);
+            }
+            Key::Char(c) if !c.is_control() => {
+                self.query.push(*c);
+                // the match shown may still match
+                self.find(self.found.or(self.start(entries)), entries);
+            }
+            Key::Ctrl('g') => return Step::Cancel { pass: false },
+            Key::Ctrl('c') => return Step::Cancel { pass: true },
+            Key::Esc => return Step::Accept { pass: false },
+            _ => return Step::Accept { pass: true },
+        }
+        Step::Searching
+    }
+
+    /// Where a search without a match starts, from the newest entry.
+    fn start(&self, entries: &[String]) -> Option<(usize, usize)> {
+        match self.reverse {
+            true => entries
+                .len()
+                .checked_sub(1)
+                .map(|entry| (entry, usize::MAX)),
+            false => None,
+        }
+    }
+
+    /// Looks for the query from the entry and offset `from` on, in the direction of the
+    /// search. Without a match the one shown stays and the search has failed.
+    fn find(&mut self, from: Option<(usize, usize)>, entries: &[String]) {
+        self.failed = false;
+        if self.query.is_empty() {
+            return;
+        }
+        let Some((start, offset)) = from else {
+            self.failed = true;
+            return;
+        };
+        let query = self.query.as_str();
+        let found = if self.reverse {
+            (0..=start.min(entries.len().saturating_sub(1)))
+                .rev()
+                .find_map(|i| {
+                    let limit = if i == start { offset } else { usize::MAX };
+                    let found = entries[i].match_indices(query).map(|(at, _)| at);
+                    found.filter(|at| *at <= limit).last().map(|at| (i, at))
+                })
+        } else {
+            (start..entries.len()).find_map(|i| {
+                let limit = if i == start { offset } else { 0 };
+                let mut found = entries[i].match_indices(query).map(|(at, _)| at);
+                found.find(|at| *at >= limit).map(|at| (i, at))
+            })
+        };
+        match found {
+            Some(found) => self.found = Some(found),
+            None => self.failed = true,
+        }
+    }
+
+    /// Shows the search in the editor.
+    pub fn show(&self, editor: &mut Editor, entries: &[String]) {
+        editor.set_prompt(format!(
+            "({}{}i-search)`{}': ",
+            if self.failed { "failed " } else { "" },
+            if self.reverse { "reverse-" } else { "" },
+            self.query
+        ));
+        match self.found {
+            Some((entry, offset)) => {
+                editor.set_line(entries[entry].clone());
+                editor.set_cursor(offset);
+                editor.set_highlight(Some(offset..offset + self.query.len()));
+            }
+            None => {
+                editor.set_line(self.line.clone());
+                editor.set_cursor(self.cursor);
+            }
+        }
+    }
+
+    /// Ends the search with the line that was found, to edit or run it.
+    pub fn accept(self, editor: &mut Editor, entries: &[String]) {
+        editor.set_prompt(self.prompt);
+        if let Some((entry, offset)) = self.found {
+            editor.set_line(entries[entry].clone());
+            editor.set_cursor(offset);
+        }
+        editor.set_highlight(None);
+    }
+
+    /// Ends the search with the line it started with.
+    pub fn cancel(self, editor: &mut Editor) {
+        editor.set_prompt(self.prompt);
+        editor.set_line(self.line);
+        editor.set_cursor(self.cursor);
+    }
+}
@@ -0,0 +1,57 @@
+//! The signals an interactive shell ignores. Keys like Ctrl-C and Ctrl-Z send their signals to
+//! the foreground process group, which the shell leaves to the job it runs, and in raw mode at
+//! the prompt the shell reads them as keys. The shell's children get the default actions back.
+//! While `wait` waits, Ctrl-C interrupts it.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Whether `SIGINT` came while it was caught.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// `SIGINT` and `SIGQUIT` would kill the shell, the others stop it.
+const IGNORED: [libc::c_int; 5] = [
+    libc::SIGINT,
+    libc::SIGQUIT,
+    libc::SIGTSTP,
+    libc::SIGTTIN,
+    libc::SIGTTOU,
+];
+
+pub fn ignore() {
+    for signal in IGNORED {
+        unsafe { libc::signal(signal, libc::SIG_IGN) };
+    }
+}
+
+/// Gives the signals the shell ignores their default actions, in a child that is about to
+/// run a command. It does not allocate, so it is safe to call between fork and exec.
+pub fn reset() {
+    for signal in IGNORED {
+        unsafe { libc::signal(signal, libc::SIG_DFL) };
+    }
+}
+
+extern "C" fn interrupt(_: libc::c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Catches `SIGINT` instead of ignoring it. The handler does not restart system calls, so a
+/// blocking `waitpid` returns with `EINTR` and [`interrupted`] tells why.
+pub fn catch_interrupt() {
+    INTERRUPTED.store(false, Ordering::SeqCst);
+    unsafe {
+        let mut action: libc::sigaction = std::mem::zeroed();
+        action.sa_sigaction = interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        libc::sigemptyset(&mut action.sa_mask);
+        libc::sigaction(libc::SIGINT, &action, std::ptr::null_mut());
+    }
+}
+
+/// Ignores `SIGINT` again after [`catch_interrupt`].
+pub fn ignore_interrupt() {
+    unsafe { libc::signal(libc::SIGINT, libc::SIG_IGN) };
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
@@ -0,0 +1,118 @@
+//! The shell's variables. They start out as a copy of the environment, and the exported ones
+//! make up the environment of spawned commands.
+
+use std::collections::HashMap;
+
+use crate::{Result, ShellError};
+
+#[derive(Debug, Default)]
+pub struct Var {
+    /// `None` for a variable that only has attributes, like after `export NAME`.
+    pub value: Option<String>,
+    pub exported: bool,
+    pub readonly: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct Variables {
+    vars: HashMap<String, Var>,
+}
+
+impl Variables {
+    pub fn from_env() -> Self {
+        Self {
+            vars: std::env::vars()
+                .map(|(name, value)| {
+                    let var = Var {
+                        value: Some(value),
+                        exported: true,
+                        readonly: false,
+                    };
+                    (name, var)
+                })
+                .collect(),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.vars.get(name)?.value.as_deref()
+    }
+
+    pub fn set(&mut self, name: &str, value: String) -> Result<()> {
+        self.writable(name)?.value = Some(value);
+        Ok(())
+    }
+
+    /// Marks a variable for export, assigning it first if a value is given.
+    pub fn export(&mut self, name: &str, value: Option<String>) -> Result<()> {
+        let var = self.writable(name)?;
+        if value.is_some() {
+            var.value = value;
+        }
+        var.exported = true;
+        Ok(())
+    }
+
+    /// Marks a variable as read only, assigning it first if a value is given.
+    pub fn readonly(&mut self, name: &str, value: Option<String>) -> Result<()> {
+        let var = self.writable(name)?;
+        if value.is_some() {
+            var.value = value;
+        }
+        var.readonly = true;
+        Ok(())
+    }
+
+    pub fn unset(&mut self, name: &str) -> Result<()> {
+        if self.vars.get(name).is_some_and(|var| var.readonly) {
+            return Err(ShellError::Readonly(name.into()));
+        }
+        self.vars.remove(name);
+        Ok(())
+    }
+
+    fn writable(&mut self, name: &str) -> Result<&mut Var> {
+        let var = self.vars.entry(name.into()).or_default();
+        if var.readonly {
+            return Err(ShellError::Readonly(name.into()));
+        }
+        Ok(var)
+    }
+
+    /// The exported variables that have a value, i.e. the environment of a child process.
+    pub fn exported(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.vars.iter().filter_map(|(name, var)| {
+            var.exported
+                .then_some(())
+                .and(var.value.as_deref())
+                .map(|value| (name.as_str(), value))
+        })
+    }
+
+    /// All variables, sorted by name.
+    pub fn sorted(&self) -> Vec<(&str, &Var)> {
+        let mut vars: Vec<_> = self.vars.iter().map(|(n, v)| (n.as_str(), v)).collect();
+        vars.sort_by_key(|(name, _)| *name);
+        vars
+    }
+}
+
+/// Returns whether `name` can be the name of a variable.
+pub fn is_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Quotes a value so that the shell reads it back unchanged.
+pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', r"'\''"))
+}
+
+/// Quotes a word only if it has characters that are special to the shell.
+pub fn quote_word(word: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_./=:,+%@".contains(c);
+    if !word.is_empty() && word.chars().all(plain) {
+        return word.into();
+    }
+    quote(word)
+}